@@ -3,6 +3,11 @@ set -x
 cargo build --release
 sudo systemctl stop ya_web3_proxy
 sudo cp target/release/ya_web3_proxy /usr/bin/ya_web3_proxy
+# install sample config on first deployment, existing config is never overwritten
+if [ ! -f /etc/ya_web3_proxy/proxy.toml ]; then
+  sudo mkdir -p /etc/ya_web3_proxy
+  sudo cp deployment/proxy.toml /etc/ya_web3_proxy/proxy.toml
+fi
 sudo systemctl start ya_web3_proxy
 # check if new version is properly installed
 sleep 1
@@ -0,0 +1,331 @@
+use crate::ServerData;
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, Method, Uri};
+use actix_web::web::Data;
+use actix_web::HttpResponse;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+/// Access level of admin API client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AdminRole {
+    /// Can only read history, problems and settings
+    Read,
+    /// Can also change problems, routing, reload config and delete history
+    ReadWrite,
+}
+
+impl AdminRole {
+    /// Reading endpoints use GET, everything else changes the state of the proxy
+    pub fn required_for(method: &Method) -> AdminRole {
+        if method == Method::GET || method == Method::HEAD || method == Method::OPTIONS {
+            AdminRole::Read
+        } else {
+            AdminRole::ReadWrite
+        }
+    }
+}
+
+/// Sent as `Authorization: Bearer <token>`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminToken {
+    pub token: String,
+    pub role: AdminRole,
+}
+
+/// Sent as `Authorization: Basic <base64 of username:password>`, used by the browser login prompt
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminUser {
+    pub username: String,
+    pub password: String,
+    pub role: AdminRole,
+}
+
+/// Access to the /api scope. Admin API is open to anyone if neither tokens nor users are set.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub tokens: Vec<AdminToken>,
+    pub users: Vec<AdminUser>,
+    /// Origins allowed by CORS on the admin API, any origin is allowed if empty
+    pub cors_allowed_origins: Vec<String>,
+}
+
+/// Compares secrets in time independent of the position of the first difference
+pub fn secrets_equal(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+impl AdminConfig {
+    /// CORS middleware fails to start workers on origins it cannot use, so they are rejected
+    /// when the config is loaded
+    pub fn check_cors_allowed_origins(&self) -> Result<(), String> {
+        for origin in &self.cors_allowed_origins {
+            if origin == "*" {
+                return Err(
+                    "\"*\" is not allowed, leave the list empty to allow any origin".into(),
+                );
+            }
+            let uri = origin
+                .parse::<Uri>()
+                .map_err(|e| format!("{origin} is not a valid origin: {e}"))?;
+            let is_origin = uri.scheme().is_some()
+                && uri.host().is_some()
+                && uri.path_and_query().map(|path| path.as_str()) == Some("/")
+                && !origin.ends_with('/');
+            if !is_origin {
+                return Err(format!(
+                    "{origin} is not a valid origin, expected scheme and host like https://example.com"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn auth_enabled(&self) -> bool {
+        !self.tokens.is_empty() || !self.users.is_empty()
+    }
+
+    /// Role of the client sending given Authorization header, none if credentials are not valid
+    pub fn role(&self, authorization: Option<&str>) -> Option<AdminRole> {
+        let (scheme, credentials) = authorization?.trim().split_once(' ')?;
+        let credentials = credentials.trim();
+        if scheme.eq_ignore_ascii_case("bearer") {
+            self.tokens
+                .iter()
+                .find(|token| secrets_equal(&token.token, credentials))
+                .map(|token| token.role)
+        } else if scheme.eq_ignore_ascii_case("basic") {
+            let decoded = base64::engine::general_purpose::STANDARD
+                .decode(credentials)
+                .ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (username, password) = decoded.split_once(':')?;
+            self.users
+                .iter()
+                .find(|user| user.username == username && secrets_equal(&user.password, password))
+                .map(|user| user.role)
+        } else {
+            None
+        }
+    }
+}
+
+/// Middleware checking credentials of admin API requests against the current config
+pub struct AdminAuth;
+
+impl<S, B> Transform<S, ServiceRequest> for AdminAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = AdminAuthMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AdminAuthMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct AdminAuthMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for AdminAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            // Without the config it is unknown who can access the API, so the request is rejected
+            let Some(server_data) = req.app_data::<Data<Box<ServerData>>>().cloned() else {
+                log::error!("Admin API request {} rejected, no server data", req.path());
+                let response =
+                    HttpResponse::InternalServerError().body("Server data not available");
+                return Ok(req.into_response(response).map_into_right_body());
+            };
+            // config lock is released before the handler runs, config reload takes it for writing
+            {
+                let config = server_data.config.read().await;
+                if config.admin.auth_enabled() {
+                    let authorization = req
+                        .headers()
+                        .get(header::AUTHORIZATION)
+                        .and_then(|value| value.to_str().ok());
+                    let required_role = AdminRole::required_for(req.method());
+                    let response = match config.admin.role(authorization) {
+                        Some(role) if role >= required_role => None,
+                        Some(_) => {
+                            Some(HttpResponse::Forbidden().body("Read-write access required"))
+                        }
+                        None => Some(
+                            HttpResponse::Unauthorized()
+                                .insert_header((
+                                    header::WWW_AUTHENTICATE,
+                                    "Basic realm=\"web3_proxy\"",
+                                ))
+                                .body("Unauthorized"),
+                        ),
+                    };
+                    if let Some(response) = response {
+                        log::warn!(
+                            "Admin API request {} {} rejected with {}",
+                            req.method(),
+                            req.path(),
+                            response.status()
+                        );
+                        return Ok(req.into_response(response).map_into_right_body());
+                    }
+                }
+            }
+            service
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AdminConfig {
+        AdminConfig {
+            tokens: vec![
+                AdminToken {
+                    token: "write-token".to_string(),
+                    role: AdminRole::ReadWrite,
+                },
+                AdminToken {
+                    token: "read-token".to_string(),
+                    role: AdminRole::Read,
+                },
+            ],
+            users: vec![AdminUser {
+                username: "viewer".to_string(),
+                password: "pa:ss".to_string(),
+                role: AdminRole::Read,
+            }],
+            cors_allowed_origins: vec![],
+        }
+    }
+
+    fn basic(credentials: &str) -> String {
+        format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode(credentials)
+        )
+    }
+
+    #[test]
+    fn secrets_equal_compares_whole_value() {
+        assert!(secrets_equal("secret", "secret"));
+        assert!(!secrets_equal("secret", "secreT"));
+        assert!(!secrets_equal("secret", "secret2"));
+        assert!(!secrets_equal("secret", ""));
+    }
+
+    #[test]
+    fn bearer_tokens() {
+        let config = config();
+        assert_eq!(
+            config.role(Some("Bearer write-token")),
+            Some(AdminRole::ReadWrite)
+        );
+        assert_eq!(
+            config.role(Some("Bearer read-token")),
+            Some(AdminRole::Read)
+        );
+        assert_eq!(config.role(Some("Bearer other-token")), None);
+        assert_eq!(config.role(None), None);
+    }
+
+    #[test]
+    fn scheme_is_case_insensitive() {
+        let config = config();
+        assert_eq!(
+            config.role(Some("bearer write-token")),
+            Some(AdminRole::ReadWrite)
+        );
+        assert_eq!(
+            config.role(Some("BEARER write-token")),
+            Some(AdminRole::ReadWrite)
+        );
+        let basic = basic("viewer:pa:ss").replacen("Basic", "bAsIc", 1);
+        assert_eq!(config.role(Some(&basic)), Some(AdminRole::Read));
+    }
+
+    #[test]
+    fn basic_auth() {
+        let config = config();
+        // password can contain colons, username cannot
+        assert_eq!(
+            config.role(Some(&basic("viewer:pa:ss"))),
+            Some(AdminRole::Read)
+        );
+        assert_eq!(config.role(Some(&basic("viewer:wrong"))), None);
+        assert_eq!(config.role(Some(&basic("other:pa:ss"))), None);
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        let config = config();
+        assert_eq!(config.role(Some("write-token")), None);
+        assert_eq!(config.role(Some("Basic not-base64!")), None);
+        assert_eq!(config.role(Some(&basic("no-colon"))), None);
+        assert_eq!(config.role(Some("Digest write-token")), None);
+        assert_eq!(config.role(Some("")), None);
+    }
+
+    #[test]
+    fn read_role_cannot_change_state() {
+        let config = config();
+        let role = config.role(Some("Bearer read-token")).unwrap();
+        assert!(role >= AdminRole::required_for(&Method::GET));
+        assert!(role < AdminRole::required_for(&Method::POST));
+        assert!(role < AdminRole::required_for(&Method::DELETE));
+        let role = config.role(Some("Bearer write-token")).unwrap();
+        assert!(role >= AdminRole::required_for(&Method::POST));
+    }
+
+    #[test]
+    fn cors_origins_are_checked() {
+        let mut config = config();
+        config.cors_allowed_origins = vec!["https://example.com".to_string()];
+        assert!(config.check_cors_allowed_origins().is_ok());
+        for origin in [
+            "*",
+            "example.com",
+            "https://example.com/app",
+            "not an origin",
+        ] {
+            config.cors_allowed_origins = vec![origin.to_string()];
+            assert!(config.check_cors_allowed_origins().is_err(), "{origin}");
+        }
+    }
+}
@@ -0,0 +1,349 @@
+use crate::upstream::KeyRouting;
+use crate::ParsedRequest;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Settings of the response cache
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    pub max_entries: usize,
+    /// How long eth_blockNumber result is reused
+    pub block_number_ttl: Duration,
+}
+
+/// Outcome of cache lookup, recorded in call history
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+}
+
+/// How long result of the call can be reused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CacheRule {
+    /// Result never changes, e.g. chain id or mined block
+    Permanent,
+    /// Result changes over time, e.g. latest block number
+    Ttl(Duration),
+}
+
+struct CacheEntry {
+    result: serde_json::Value,
+    expires: Option<Instant>,
+    seq: u64,
+}
+
+/// Caches results of JSON-RPC calls that are immutable or change slowly.
+/// Only the `result` field is stored, so cached response gets id of the request it answers.
+pub struct ResponseCache {
+    config: CacheConfig,
+    entries: HashMap<String, CacheEntry>,
+    /// Insertion order used for eviction, items are skipped if the entry was replaced since
+    order: VecDeque<(String, u64)>,
+    next_seq: u64,
+}
+
+fn is_block_number(param: Option<&serde_json::Value>) -> bool {
+    param
+        .and_then(|param| param.as_str())
+        .map(|param| param.starts_with("0x"))
+        .unwrap_or(false)
+}
+
+impl ResponseCache {
+    pub fn new(config: CacheConfig) -> ResponseCache {
+        ResponseCache {
+            config,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            next_seq: 0,
+        }
+    }
+
+    /// Applies new settings, entries over the new limit are evicted
+    pub fn update(&mut self, config: CacheConfig) {
+        if !config.enabled {
+            self.entries.clear();
+            self.order.clear();
+        }
+        self.config = config;
+        self.evict();
+    }
+
+    fn rule(&self, req: &ParsedRequest) -> Option<CacheRule> {
+        if req.parse_error.is_some() {
+            return None;
+        }
+        match req.method.as_str() {
+            "eth_chainId" | "net_version" => Some(CacheRule::Permanent),
+            "eth_getBlockByHash" | "eth_getTransactionReceipt" => Some(CacheRule::Permanent),
+            // block tags like latest or pending point to different blocks over time
+            "eth_getBlockByNumber" if is_block_number(req.params.first()) => {
+                Some(CacheRule::Permanent)
+            }
+            "eth_blockNumber" => Some(CacheRule::Ttl(self.config.block_number_ttl)),
+            _ => None,
+        }
+    }
+
+    /// Checks if every call of the request can be served from cache
+    pub fn is_cacheable(&self, requests: &[ParsedRequest]) -> bool {
+        self.config.enabled
+            && !requests.is_empty()
+            && requests.iter().all(|req| self.rule(req).is_some())
+    }
+
+    fn entry_key(scope: &str, req: &ParsedRequest) -> String {
+        format!(
+            "{}|{}|{}",
+            scope,
+            req.method,
+            serde_json::Value::Array(req.params.clone())
+        )
+    }
+
+    /// Builds response body from cache, only if all calls of the request are cached
+    pub fn get_response(
+        &mut self,
+        routing: &KeyRouting,
+        requests: &[ParsedRequest],
+        is_batch: bool,
+    ) -> Option<String> {
+        if !self.is_cacheable(requests) {
+            return None;
+        }
+        let scope = routing.scope();
+        let now = Instant::now();
+        let mut responses = Vec::with_capacity(requests.len());
+        for req in requests {
+            let key = ResponseCache::entry_key(&scope, req);
+            let entry = self.entries.get(&key)?;
+            if entry.expires.map(|expires| expires <= now).unwrap_or(false) {
+                self.entries.remove(&key);
+                return None;
+            }
+            responses.push(json!({"jsonrpc": "2.0", "id": req.id, "result": entry.result}));
+        }
+        if is_batch {
+            Some(serde_json::Value::Array(responses).to_string())
+        } else {
+            responses.pop().map(|response| response.to_string())
+        }
+    }
+
+    /// Stores successful results from upstream response, responses are matched to requests by id
+    pub fn store_response(&mut self, routing: &KeyRouting, requests: &[ParsedRequest], body: &str) {
+        if !self.config.enabled {
+            return;
+        }
+        let Ok(body_json) = serde_json::from_str::<serde_json::Value>(body) else {
+            return;
+        };
+        let responses = match body_json {
+            serde_json::Value::Array(responses) => responses,
+            response => vec![response],
+        };
+        let scope = routing.scope();
+        let now = Instant::now();
+        for req in requests {
+            let Some(rule) = self.rule(req) else {
+                continue;
+            };
+            let Some(response) = responses.iter().find(|response| response["id"] == req.id) else {
+                continue;
+            };
+            // null means not found yet, e.g. receipt of pending transaction
+            if response.get("error").is_some() || response["result"].is_null() {
+                continue;
+            }
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            let key = ResponseCache::entry_key(&scope, req);
+            self.entries.insert(
+                key.clone(),
+                CacheEntry {
+                    result: response["result"].clone(),
+                    expires: match rule {
+                        CacheRule::Permanent => None,
+                        CacheRule::Ttl(ttl) => Some(now + ttl),
+                    },
+                    seq,
+                },
+            );
+            self.order.push_back((key, seq));
+        }
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        if self.entries.len() > self.config.max_entries {
+            let now = Instant::now();
+            self.entries
+                .retain(|_, entry| entry.expires.map(|expires| expires > now).unwrap_or(true));
+        }
+        while self.entries.len() > self.config.max_entries {
+            let Some((key, seq)) = self.order.pop_front() else {
+                break;
+            };
+            if self.entries.get(&key).map(|entry| entry.seq) == Some(seq) {
+                self.entries.remove(&key);
+            }
+        }
+        // drop stale items of replaced or expired entries
+        if self.order.len() > 2 * self.config.max_entries.max(self.entries.len()) {
+            let entries = &self.entries;
+            self.order
+                .retain(|(key, seq)| entries.get(key).map(|entry| entry.seq) == Some(*seq));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(max_entries: usize) -> ResponseCache {
+        ResponseCache::new(CacheConfig {
+            enabled: true,
+            max_entries,
+            block_number_ttl: Duration::from_secs(60),
+        })
+    }
+
+    fn response(id: i64, result: serde_json::Value) -> String {
+        json!({"jsonrpc": "2.0", "id": id, "result": result}).to_string()
+    }
+
+    #[test]
+    fn block_tags_are_not_cacheable() {
+        let cache = cache(10);
+        assert!(cache.is_cacheable(&[ParsedRequest::new(json!(1), "eth_chainId", vec![])]));
+        assert!(cache.is_cacheable(&[ParsedRequest::new(
+            json!(1),
+            "eth_getBlockByNumber",
+            vec![json!("0x10"), json!(false)]
+        )]));
+        assert!(!cache.is_cacheable(&[ParsedRequest::new(
+            json!(1),
+            "eth_getBlockByNumber",
+            vec![json!("latest"), json!(false)]
+        )]));
+        assert!(!cache.is_cacheable(&[ParsedRequest::new(json!(1), "eth_call", vec![])]));
+        // batch is served from cache only if all its calls can be
+        assert!(!cache.is_cacheable(&[
+            ParsedRequest::new(json!(1), "eth_chainId", vec![]),
+            ParsedRequest::new(json!(2), "eth_gasPrice", vec![]),
+        ]));
+    }
+
+    #[test]
+    fn cached_response_gets_id_of_the_request() {
+        let mut cache = cache(10);
+        let routing = KeyRouting::default();
+        cache.store_response(
+            &routing,
+            &[ParsedRequest::new(json!(1), "eth_chainId", vec![])],
+            &response(1, json!("0x89")),
+        );
+        let body = cache
+            .get_response(
+                &routing,
+                &[ParsedRequest::new(json!(5), "eth_chainId", vec![])],
+                false,
+            )
+            .unwrap();
+        assert_eq!(body, response(5, json!("0x89")));
+        let body = cache
+            .get_response(
+                &routing,
+                &[ParsedRequest::new(json!(6), "eth_chainId", vec![])],
+                true,
+            )
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            json!([{"jsonrpc": "2.0", "id": 6, "result": "0x89"}])
+        );
+    }
+
+    #[test]
+    fn null_results_and_errors_are_not_stored() {
+        let mut cache = cache(10);
+        let routing = KeyRouting::default();
+        let receipt = [ParsedRequest::new(
+            json!(1),
+            "eth_getTransactionReceipt",
+            vec![json!("0xabc")],
+        )];
+        cache.store_response(&routing, &receipt, &response(1, json!(null)));
+        assert!(cache.get_response(&routing, &receipt, false).is_none());
+        let error = json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32000, "message": "x"}});
+        cache.store_response(&routing, &receipt, &error.to_string());
+        assert!(cache.get_response(&routing, &receipt, false).is_none());
+    }
+
+    #[test]
+    fn routing_scopes_are_separate() {
+        let mut cache = cache(10);
+        let mainnet = KeyRouting {
+            chain: Some("mainnet".to_string()),
+            ..Default::default()
+        };
+        let testnet = KeyRouting {
+            chain: Some("testnet".to_string()),
+            ..Default::default()
+        };
+        let chain_id = [ParsedRequest::new(json!(1), "eth_chainId", vec![])];
+        cache.store_response(&mainnet, &chain_id, &response(1, json!("0x1")));
+        assert!(cache.get_response(&testnet, &chain_id, false).is_none());
+        assert!(cache.get_response(&mainnet, &chain_id, false).is_some());
+    }
+
+    #[test]
+    fn expired_entries_are_not_used() {
+        let mut cache = ResponseCache::new(CacheConfig {
+            enabled: true,
+            max_entries: 10,
+            block_number_ttl: Duration::ZERO,
+        });
+        let routing = KeyRouting::default();
+        let block_number = [ParsedRequest::new(json!(1), "eth_blockNumber", vec![])];
+        cache.store_response(&routing, &block_number, &response(1, json!("0x10")));
+        assert!(cache.get_response(&routing, &block_number, false).is_none());
+    }
+
+    #[test]
+    fn oldest_entries_are_evicted() {
+        let mut cache = cache(2);
+        let routing = KeyRouting::default();
+        let receipts: Vec<ParsedRequest> = (1..=3)
+            .map(|id| {
+                ParsedRequest::new(
+                    json!(id),
+                    "eth_getTransactionReceipt",
+                    vec![json!(format!("0x{id}"))],
+                )
+            })
+            .collect();
+        for (idx, id) in (1..=3).enumerate() {
+            cache.store_response(
+                &routing,
+                &receipts[idx..=idx],
+                &response(id, json!({"status": "0x1"})),
+            );
+        }
+        assert!(cache
+            .get_response(&routing, &receipts[0..1], false)
+            .is_none());
+        assert!(cache
+            .get_response(&routing, &receipts[1..2], false)
+            .is_some());
+        assert!(cache
+            .get_response(&routing, &receipts[2..3], false)
+            .is_some());
+    }
+}
@@ -0,0 +1,285 @@
+use crate::method_pattern::method_matches;
+use crate::upstream::{KeyRouting, UpstreamError};
+use crate::ParsedRequest;
+use actix_web::http::StatusCode;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// Read-only methods whose identical calls can share one response
+const COALESCED_METHODS: [&str; 10] = [
+    "eth_get*",
+    "eth_blockNumber",
+    "eth_chainId",
+    "net_version",
+    "eth_call",
+    "eth_estimateGas",
+    "eth_gasPrice",
+    "eth_maxPriorityFeePerGas",
+    "eth_feeHistory",
+    "eth_syncing",
+];
+
+/// Filter changes are consumed by the call, so every caller has to send its own
+const NOT_COALESCED_METHODS: [&str; 1] = ["eth_getFilter*"];
+
+fn is_coalesced(method: &str) -> bool {
+    COALESCED_METHODS
+        .iter()
+        .any(|pattern| method_matches(pattern, method))
+        && !NOT_COALESCED_METHODS
+            .iter()
+            .any(|pattern| method_matches(pattern, method))
+}
+
+/// Upstream response shared by the leading request with identical requests waiting for it
+pub struct SharedResponse {
+    pub result: Result<(StatusCode, String), UpstreamError>,
+    pub upstream: Option<String>,
+    /// Calls of the leading request, needed to give the response ids of the waiting request
+    pub requests: Vec<ParsedRequest>,
+}
+
+impl SharedResponse {
+    /// Result with response ids replaced by ids of the waiting request
+    pub fn result_for(
+        &self,
+        requests: &[ParsedRequest],
+    ) -> Result<(StatusCode, String), UpstreamError> {
+        let (status, body) = self.result.clone()?;
+        let Ok(mut body_json) = serde_json::from_str::<serde_json::Value>(&body) else {
+            return Ok((status, body));
+        };
+        match &mut body_json {
+            serde_json::Value::Array(responses) => {
+                for response in responses {
+                    if let Some(req) = self
+                        .requests
+                        .iter()
+                        .position(|req| req.id == response["id"])
+                        .and_then(|idx| requests.get(idx))
+                    {
+                        response["id"] = req.id.clone();
+                    }
+                }
+            }
+            serde_json::Value::Object(response) => {
+                if let Some(req) = requests.first() {
+                    response.insert("id".to_string(), req.id.clone());
+                }
+            }
+            _ => return Ok((status, body)),
+        }
+        Ok((status, body_json.to_string()))
+    }
+}
+
+pub enum InFlight {
+    /// No identical request is in flight, this one goes upstream
+    Leader(InFlightLeader),
+    /// Identical request is in flight, its response can be awaited
+    Waiter(broadcast::Receiver<Arc<SharedResponse>>),
+}
+
+/// Requests currently sent to upstreams, by method and params of their calls
+#[derive(Default)]
+pub struct InFlightRequests {
+    requests: Arc<Mutex<HashMap<String, broadcast::Sender<Arc<SharedResponse>>>>>,
+}
+
+impl InFlightRequests {
+    /// Identifies the request regardless of its ids, none if the request cannot be shared.
+    /// Only read-only methods are shared, e.g. two eth_newFilter calls have to create two filters.
+    /// Batch of one call is answered with an array, so it is not shared with a single call.
+    pub fn coalesce_key(
+        routing: &KeyRouting,
+        requests: &[ParsedRequest],
+        is_batch: bool,
+    ) -> Option<String> {
+        if requests.is_empty()
+            || requests
+                .iter()
+                .any(|req| req.parse_error.is_some() || !is_coalesced(&req.method))
+        {
+            return None;
+        }
+        let calls = requests
+            .iter()
+            .map(|req| {
+                format!(
+                    "{}{}",
+                    req.method,
+                    serde_json::Value::from(req.params.clone())
+                )
+            })
+            .collect::<Vec<String>>();
+        let kind = if is_batch { "batch" } else { "single" };
+        Some(format!("{}|{}|{}", routing.scope(), kind, calls.join("|")))
+    }
+
+    pub fn join(&self, key: &str) -> InFlight {
+        let mut requests = self.requests.lock().unwrap();
+        if let Some(sender) = requests.get(key) {
+            return InFlight::Waiter(sender.subscribe());
+        }
+        let (sender, _) = broadcast::channel(1);
+        requests.insert(key.to_string(), sender);
+        InFlight::Leader(InFlightLeader {
+            requests: self.requests.clone(),
+            key: key.to_string(),
+        })
+    }
+}
+
+/// Held by the request that goes upstream. If dropped before finishing,
+/// e.g. when the client disconnects, waiting requests are woken up to send their own calls.
+pub struct InFlightLeader {
+    requests: Arc<Mutex<HashMap<String, broadcast::Sender<Arc<SharedResponse>>>>>,
+    key: String,
+}
+
+impl InFlightLeader {
+    pub fn finish(self, response: SharedResponse) {
+        let sender = self.requests.lock().unwrap().remove(&self.key);
+        if let Some(sender) = sender {
+            // error only means there is no one waiting
+            let _ = sender.send(Arc::new(response));
+        }
+    }
+}
+
+impl Drop for InFlightLeader {
+    fn drop(&mut self) {
+        self.requests.lock().unwrap().remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn shared(requests: Vec<ParsedRequest>, body: serde_json::Value) -> SharedResponse {
+        SharedResponse {
+            result: Ok((StatusCode::OK, body.to_string())),
+            upstream: None,
+            requests,
+        }
+    }
+
+    fn body_for(shared: &SharedResponse, requests: &[ParsedRequest]) -> serde_json::Value {
+        let (_, body) = shared.result_for(requests).unwrap();
+        serde_json::from_str(&body).unwrap()
+    }
+
+    #[test]
+    fn result_for_single_call_replaces_id() {
+        let shared = shared(
+            vec![ParsedRequest::new(json!(1), "eth_blockNumber", vec![])],
+            json!({"jsonrpc": "2.0", "id": 1, "result": "0x10"}),
+        );
+        let body = body_for(
+            &shared,
+            &[ParsedRequest::new(json!("abc"), "eth_blockNumber", vec![])],
+        );
+        assert_eq!(
+            body,
+            json!({"jsonrpc": "2.0", "id": "abc", "result": "0x10"})
+        );
+    }
+
+    #[test]
+    fn result_for_batch_replaces_ids_by_position() {
+        let shared = shared(
+            vec![
+                ParsedRequest::new(json!(1), "eth_blockNumber", vec![]),
+                ParsedRequest::new(json!(2), "eth_chainId", vec![]),
+            ],
+            json!([
+                {"jsonrpc": "2.0", "id": 2, "result": "0x1"},
+                {"jsonrpc": "2.0", "id": 1, "result": "0x10"},
+            ]),
+        );
+        let body = body_for(
+            &shared,
+            &[
+                ParsedRequest::new(json!(7), "eth_blockNumber", vec![]),
+                ParsedRequest::new(json!(8), "eth_chainId", vec![]),
+            ],
+        );
+        assert_eq!(
+            body,
+            json!([
+                {"jsonrpc": "2.0", "id": 8, "result": "0x1"},
+                {"jsonrpc": "2.0", "id": 7, "result": "0x10"},
+            ])
+        );
+    }
+
+    #[test]
+    fn result_for_keeps_errors() {
+        let shared = SharedResponse {
+            result: Err(UpstreamError::Timeout),
+            upstream: None,
+            requests: vec![ParsedRequest::new(json!(1), "eth_blockNumber", vec![])],
+        };
+        assert!(shared
+            .result_for(&[ParsedRequest::new(json!(2), "eth_blockNumber", vec![])])
+            .is_err());
+    }
+
+    #[test]
+    fn coalesce_key_separates_batch_from_single_call() {
+        let routing = KeyRouting::default();
+        let requests = vec![ParsedRequest::new(json!(1), "eth_blockNumber", vec![])];
+        let single = InFlightRequests::coalesce_key(&routing, &requests, false);
+        let batch = InFlightRequests::coalesce_key(&routing, &requests, true);
+        assert!(single.is_some());
+        assert_ne!(single, batch);
+    }
+
+    #[test]
+    fn coalesce_key_ignores_ids() {
+        let routing = KeyRouting::default();
+        assert_eq!(
+            InFlightRequests::coalesce_key(
+                &routing,
+                &[ParsedRequest::new(json!(1), "eth_chainId", vec![])],
+                false
+            ),
+            InFlightRequests::coalesce_key(
+                &routing,
+                &[ParsedRequest::new(json!(2), "eth_chainId", vec![])],
+                false
+            ),
+        );
+    }
+
+    #[test]
+    fn stateful_methods_are_not_coalesced() {
+        let routing = KeyRouting::default();
+        for method in [
+            "eth_newFilter",
+            "eth_newBlockFilter",
+            "eth_newPendingTransactionFilter",
+            "eth_getFilterChanges",
+            "eth_uninstallFilter",
+            "eth_sendRawTransaction",
+        ] {
+            let requests = [ParsedRequest::new(json!(1), method, vec![])];
+            assert_eq!(
+                InFlightRequests::coalesce_key(&routing, &requests, false),
+                None,
+                "{method}"
+            );
+        }
+        // batch is shared only if all its calls can be
+        let batch = [
+            ParsedRequest::new(json!(1), "eth_getBalance", vec![]),
+            ParsedRequest::new(json!(2), "eth_newFilter", vec![]),
+        ];
+        assert_eq!(InFlightRequests::coalesce_key(&routing, &batch, true), None);
+        let requests = [ParsedRequest::new(json!(1), "eth_getLogs", vec![])];
+        assert!(InFlightRequests::coalesce_key(&routing, &requests, false).is_some());
+    }
+}
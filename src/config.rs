@@ -0,0 +1,352 @@
+use crate::admin_auth::{AdminConfig, AdminRole, AdminToken};
+use crate::cache::CacheConfig;
+use crate::error::*;
+use crate::key_access::MethodAccess;
+use crate::problems::EndpointSimulateProblems;
+use crate::rate_limit::{RateLimit, RateLimitConfig};
+use crate::stored_body::HistoryLimits;
+use crate::upstream::{
+    BalanceStrategy, KeyRouting, MethodRoute, RetryPolicy, UpstreamClientConfig, UpstreamConfig,
+};
+use crate::{err_custom_create, err_from, err_from_msg, CliOptions};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const DEFAULT_HTTP_THREADS: u64 = 2;
+const DEFAULT_HTTP_PORT: u16 = 8080;
+const DEFAULT_HTTP_ADDR: &str = "127.0.0.1";
+const DEFAULT_TARGET_ADDR: &str = "http://polygongas.org:8545";
+const DEFAULT_REQUEST_QUEUE_SIZE: usize = 10000;
+const DEFAULT_UPSTREAM_FAILURE_THRESHOLD: u32 = 3;
+const DEFAULT_UPSTREAM_COOLDOWN_SECS: u64 = 30;
+const DEFAULT_UPSTREAM_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_UPSTREAM_CONNECT_TIMEOUT_MS: u64 = 2000;
+const DEFAULT_UPSTREAM_KEEP_ALIVE_MS: u64 = 15000;
+const DEFAULT_UPSTREAM_MAX_CONNECTIONS: usize = 100;
+const DEFAULT_UPSTREAM_READ_TIMEOUT_MS: u64 = 10000;
+const DEFAULT_UPSTREAM_MAX_RESPONSE_SIZE: usize = 64 * 1024 * 1024;
+const DEFAULT_UPSTREAM_RETRIES: u32 = 0;
+const DEFAULT_UPSTREAM_RETRY_BACKOFF_MS: u64 = 100;
+const DEFAULT_UPSTREAM_RETRY_BACKOFF_MAX_MS: u64 = 2000;
+const DEFAULT_UPSTREAM_RETRY_ON_STATUS: [u16; 4] = [429, 502, 503, 504];
+const DEFAULT_HISTORY_MAX_BODY_SIZE: usize = 1024 * 1024;
+const DEFAULT_HISTORY_MEMORY_LIMIT: usize = 1024 * 1024 * 1024;
+const DEFAULT_KEY_CLEANUP_INTERVAL_SECS: u64 = 60;
+const DEFAULT_CACHE_MAX_ENTRIES: usize = 10000;
+const DEFAULT_CACHE_BLOCK_NUMBER_TTL_MS: u64 = 1000;
+
+/// Content of the TOML config file, every value is optional.
+/// Values given on the command line or in the environment take precedence over the file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub http_threads: Option<u64>,
+    pub http_port: Option<u16>,
+    pub http_addr: Option<String>,
+    /// Shortcut for single upstream, ignored if upstreams are given
+    pub target_addr: Option<String>,
+    pub upstreams: Vec<UpstreamConfig>,
+    /// Rules sending methods to particular upstreams, first matching rule is used
+    pub routes: Vec<MethodRoute>,
+    pub balance_strategy: Option<BalanceStrategy>,
+    /// Number of consecutive failures after which upstream is considered unhealthy
+    pub upstream_failure_threshold: Option<u32>,
+    /// How long unhealthy upstream is used only as a last resort
+    pub upstream_cooldown_secs: Option<u64>,
+    /// Time limit for upstream request, until response headers are received
+    pub upstream_timeout_ms: Option<u64>,
+    pub upstream_connect_timeout_ms: Option<u64>,
+    /// Time limit for receiving response body after headers arrived
+    pub upstream_read_timeout_ms: Option<u64>,
+    /// Upstream responses with longer body are rejected (bytes)
+    pub upstream_max_response_size: Option<usize>,
+    /// How many times the same upstream is called again before failing over to the next one
+    pub upstream_retries: Option<u32>,
+    /// Delay before the first retry, doubled for every next retry
+    pub upstream_retry_backoff_ms: Option<u64>,
+    pub upstream_retry_backoff_max_ms: Option<u64>,
+    /// Upstream response statuses that are retried
+    pub upstream_retry_on_status: Option<Vec<u16>>,
+    /// How long idle upstream connection is kept for reuse
+    pub upstream_keep_alive_ms: Option<u64>,
+    /// Maximum number of simultaneous upstream connections per worker
+    pub upstream_max_connections: Option<usize>,
+    /// Reuse results of immutable calls like eth_chainId or eth_getBlockByHash
+    pub cache_enabled: Option<bool>,
+    pub cache_max_entries: Option<usize>,
+    pub cache_block_number_ttl_ms: Option<u64>,
+    /// Send only one of identical read-only calls that are in flight at the same time, others share its response
+    pub coalesce_requests: Option<bool>,
+    /// Reject keys that are not defined in the keys section
+    pub registered_keys_only: Option<bool>,
+    /// Token bucket limits protecting upstreams, no limits if not set
+    pub rate_limit: RateLimitConfig,
+    pub request_queue_size: Option<usize>,
+    pub history_dir: Option<PathBuf>,
+    /// Request and response bodies longer than this are truncated in history
+    pub history_max_body_size: Option<usize>,
+    /// Memory used by history of all keys, history of least recently active keys is removed over it
+    pub history_memory_limit: Option<usize>,
+    /// Keep bodies in history compressed, saves memory at the cost of CPU
+    pub history_compress_bodies: Option<bool>,
+    /// Keys without requests for longer than this are removed with their history, never if not set.
+    /// Keys from the keys section are kept.
+    pub key_idle_ttl_secs: Option<u64>,
+    /// How often idle keys are looked for
+    pub key_cleanup_interval_secs: Option<u64>,
+    /// Authentication of the admin API and CORS settings
+    pub admin: AdminConfig,
+    pub keys: BTreeMap<String, KeyConfig>,
+}
+
+/// Key created on startup, before the first web3 call
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyConfig {
+    pub problems: EndpointSimulateProblems,
+    /// Overrides global balance strategy for this key
+    pub balance_strategy: Option<BalanceStrategy>,
+    /// Name of the only upstream used by this key
+    pub upstream: Option<String>,
+    /// Only upstreams of this chain are used by this key
+    pub chain: Option<String>,
+    /// Overrides per key rate limit for this key
+    pub rate_limit: Option<RateLimit>,
+    /// Required as `Authorization: Bearer <secret>` or `?secret=<secret>` if set
+    pub secret: Option<String>,
+    /// Key is rejected after this time, e.g. "2024-12-31T23:59:59Z"
+    pub expires: Option<chrono::DateTime<chrono::Utc>>,
+    /// Method names or globs the key can call, any method if empty
+    pub allowed_methods: Vec<String>,
+    /// Method names or globs the key cannot call, takes precedence over allowed_methods
+    pub denied_methods: Vec<String>,
+}
+
+impl KeyConfig {
+    pub fn routing(&self) -> KeyRouting {
+        KeyRouting {
+            upstream: self.upstream.clone(),
+            chain: self.chain.clone(),
+            balance_strategy: self.balance_strategy,
+        }
+    }
+
+    pub fn method_access(&self) -> MethodAccess {
+        MethodAccess {
+            allow: self.allowed_methods.clone(),
+            deny: self.denied_methods.clone(),
+        }
+    }
+}
+
+/// Settings of the proxy after merging config file with command line options
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    pub http_threads: u64,
+    pub http_port: u16,
+    pub http_addr: String,
+    pub upstreams: Vec<UpstreamConfig>,
+    pub routes: Vec<MethodRoute>,
+    pub balance_strategy: BalanceStrategy,
+    pub upstream_failure_threshold: u32,
+    pub upstream_cooldown: Duration,
+    pub upstream_client: UpstreamClientConfig,
+    pub retry_policy: RetryPolicy,
+    pub cache: CacheConfig,
+    pub coalesce_requests: bool,
+    pub registered_keys_only: bool,
+    pub rate_limit: RateLimitConfig,
+    pub request_queue_size: usize,
+    pub history_dir: Option<PathBuf>,
+    pub history_limits: HistoryLimits,
+    pub key_idle_ttl: Option<Duration>,
+    pub key_cleanup_interval: Duration,
+    pub admin: AdminConfig,
+    pub keys: BTreeMap<String, KeyConfig>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &PathBuf) -> Result<ConfigFile, Web3ProxyError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(err_from_msg!("Cannot read config file {}", path.display()))?;
+        toml::from_str(&content).map_err(err_from!())
+    }
+}
+
+impl ProxyConfig {
+    pub fn load(cli: &CliOptions) -> Result<ProxyConfig, Web3ProxyError> {
+        let file = match &cli.config {
+            Some(path) => {
+                log::info!("Loading config file {}", path.display());
+                ConfigFile::load(path)?
+            }
+            None => ConfigFile::default(),
+        };
+        let config = ProxyConfig::merge(cli, file);
+        config.check_routes();
+        config
+            .admin
+            .check_cors_allowed_origins()
+            .map_err(|e| err_custom_create!("Invalid admin.cors_allowed_origins: {e}"))?;
+        Ok(config)
+    }
+
+    fn check_routes(&self) {
+        for route in &self.routes {
+            for name in &route.upstreams {
+                if !self.upstreams.iter().any(|upstream| &upstream.name == name) {
+                    log::warn!(
+                        "Route for method {} uses unknown upstream {}",
+                        route.method,
+                        name
+                    );
+                }
+            }
+        }
+    }
+
+    fn merge(cli: &CliOptions, file: ConfigFile) -> ProxyConfig {
+        let upstreams = ProxyConfig::merge_upstreams(cli, &file);
+        ProxyConfig {
+            http_threads: cli
+                .http_threads
+                .or(file.http_threads)
+                .unwrap_or(DEFAULT_HTTP_THREADS),
+            http_port: cli
+                .http_port
+                .or(file.http_port)
+                .unwrap_or(DEFAULT_HTTP_PORT),
+            http_addr: cli
+                .http_addr
+                .clone()
+                .or(file.http_addr)
+                .unwrap_or_else(|| DEFAULT_HTTP_ADDR.to_string()),
+            upstreams,
+            routes: file.routes,
+            balance_strategy: file.balance_strategy.unwrap_or_default(),
+            upstream_failure_threshold: file
+                .upstream_failure_threshold
+                .unwrap_or(DEFAULT_UPSTREAM_FAILURE_THRESHOLD),
+            upstream_cooldown: Duration::from_secs(
+                file.upstream_cooldown_secs
+                    .unwrap_or(DEFAULT_UPSTREAM_COOLDOWN_SECS),
+            ),
+            upstream_client: UpstreamClientConfig {
+                timeout: Duration::from_millis(
+                    file.upstream_timeout_ms
+                        .unwrap_or(DEFAULT_UPSTREAM_TIMEOUT_MS),
+                ),
+                connect_timeout: Duration::from_millis(
+                    file.upstream_connect_timeout_ms
+                        .unwrap_or(DEFAULT_UPSTREAM_CONNECT_TIMEOUT_MS),
+                ),
+                read_timeout: Duration::from_millis(
+                    file.upstream_read_timeout_ms
+                        .unwrap_or(DEFAULT_UPSTREAM_READ_TIMEOUT_MS),
+                ),
+                max_response_size: file
+                    .upstream_max_response_size
+                    .unwrap_or(DEFAULT_UPSTREAM_MAX_RESPONSE_SIZE),
+                keep_alive: Duration::from_millis(
+                    file.upstream_keep_alive_ms
+                        .unwrap_or(DEFAULT_UPSTREAM_KEEP_ALIVE_MS),
+                ),
+                max_connections: file
+                    .upstream_max_connections
+                    .unwrap_or(DEFAULT_UPSTREAM_MAX_CONNECTIONS),
+            },
+            retry_policy: RetryPolicy {
+                retries: file.upstream_retries.unwrap_or(DEFAULT_UPSTREAM_RETRIES),
+                backoff: Duration::from_millis(
+                    file.upstream_retry_backoff_ms
+                        .unwrap_or(DEFAULT_UPSTREAM_RETRY_BACKOFF_MS),
+                ),
+                max_backoff: Duration::from_millis(
+                    file.upstream_retry_backoff_max_ms
+                        .unwrap_or(DEFAULT_UPSTREAM_RETRY_BACKOFF_MAX_MS),
+                ),
+                retry_on_status: file
+                    .upstream_retry_on_status
+                    .unwrap_or_else(|| DEFAULT_UPSTREAM_RETRY_ON_STATUS.to_vec()),
+            },
+            cache: CacheConfig {
+                enabled: file.cache_enabled.unwrap_or(false),
+                max_entries: file.cache_max_entries.unwrap_or(DEFAULT_CACHE_MAX_ENTRIES),
+                block_number_ttl: Duration::from_millis(
+                    file.cache_block_number_ttl_ms
+                        .unwrap_or(DEFAULT_CACHE_BLOCK_NUMBER_TTL_MS),
+                ),
+            },
+            coalesce_requests: file.coalesce_requests.unwrap_or(false),
+            registered_keys_only: file.registered_keys_only.unwrap_or(false),
+            rate_limit: file.rate_limit,
+            request_queue_size: cli
+                .request_queue_size
+                .or(file.request_queue_size)
+                .unwrap_or(DEFAULT_REQUEST_QUEUE_SIZE),
+            history_dir: cli.history_dir.clone().or(file.history_dir),
+            history_limits: HistoryLimits {
+                max_body_size: file
+                    .history_max_body_size
+                    .unwrap_or(DEFAULT_HISTORY_MAX_BODY_SIZE),
+                memory_limit: file
+                    .history_memory_limit
+                    .unwrap_or(DEFAULT_HISTORY_MEMORY_LIMIT),
+                compress_bodies: file.history_compress_bodies.unwrap_or(false),
+            },
+            key_idle_ttl: file.key_idle_ttl_secs.map(Duration::from_secs),
+            key_cleanup_interval: Duration::from_secs(
+                file.key_cleanup_interval_secs
+                    .unwrap_or(DEFAULT_KEY_CLEANUP_INTERVAL_SECS)
+                    .max(1),
+            ),
+            admin: ProxyConfig::merge_admin(cli, file.admin),
+            keys: file.keys,
+        }
+    }
+
+    /// Token given on the command line or in the environment has full access
+    fn merge_admin(cli: &CliOptions, mut admin: AdminConfig) -> AdminConfig {
+        if let Some(token) = &cli.admin_token {
+            admin.tokens.push(AdminToken {
+                token: token.clone(),
+                role: AdminRole::ReadWrite,
+            });
+        }
+        admin
+    }
+
+    fn merge_upstreams(cli: &CliOptions, file: &ConfigFile) -> Vec<UpstreamConfig> {
+        if let Some(target_addr) = &cli.target_addr {
+            return vec![UpstreamConfig::new(target_addr)];
+        }
+        if !file.upstreams.is_empty() {
+            return file
+                .upstreams
+                .iter()
+                .cloned()
+                .map(|mut upstream| {
+                    if upstream.name.is_empty() {
+                        upstream.name = upstream.url.clone();
+                    }
+                    upstream
+                })
+                .collect();
+        }
+        vec![UpstreamConfig::new(
+            file.target_addr.as_deref().unwrap_or(DEFAULT_TARGET_ADDR),
+        )]
+    }
+}
+
+#[cfg(test)]
+impl ProxyConfig {
+    /// Config built from the given file content only, command line options are left empty
+    pub fn from_toml(content: &str) -> ProxyConfig {
+        use structopt::StructOpt;
+        let cli = CliOptions::from_iter(["web3-proxy"]);
+        ProxyConfig::merge(&cli, toml::from_str(content).unwrap())
+    }
+}
@@ -13,6 +13,7 @@ pub enum ErrorBag {
     CustomError(CustomError),
     TransactionFailedError(TransactionFailedError),
     FromHexError(FromHexError),
+    TomlError(Box<toml::de::Error>),
 }
 
 impl Display for ErrorBag {
@@ -25,6 +26,7 @@ impl Display for ErrorBag {
                 write!(f, "{transaction_failed_error}")
             }
             ErrorBag::FromHexError(from_hex_error) => write!(f, "{from_hex_error:?}"),
+            ErrorBag::TomlError(toml_error) => write!(f, "{toml_error}"),
         }
     }
 }
@@ -60,3 +62,9 @@ impl From<FromHexError> for ErrorBag {
         ErrorBag::FromHexError(err)
     }
 }
+
+impl From<toml::de::Error> for ErrorBag {
+    fn from(err: toml::de::Error) -> Self {
+        ErrorBag::TomlError(Box::new(err))
+    }
+}
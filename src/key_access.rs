@@ -0,0 +1,317 @@
+use crate::admin_auth::secrets_equal;
+use crate::config::ProxyConfig;
+use crate::method_pattern::method_matches;
+use crate::problems::{rpc_error_response, SimulatedRpcError};
+use crate::ParsedRequest;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// JSON-RPC error code for methods the key is not allowed to call
+pub const METHOD_NOT_ALLOWED_ERROR_CODE: i64 = -32601;
+
+/// Reason why the request to web3 endpoint is rejected
+#[derive(Debug, Clone)]
+pub enum KeyAccessError {
+    /// Only keys from config are accepted
+    UnknownKey,
+    InvalidSecret,
+    Expired(chrono::DateTime<chrono::Utc>),
+    /// Denied methods of the request, calls that could not be parsed are listed as `<unparsed>`
+    MethodNotAllowed(Vec<String>),
+}
+
+impl std::fmt::Display for KeyAccessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyAccessError::UnknownKey => write!(f, "Unknown key"),
+            KeyAccessError::InvalidSecret => write!(f, "Invalid or missing key secret"),
+            KeyAccessError::Expired(expires) => write!(f, "Key expired at {expires}"),
+            KeyAccessError::MethodNotAllowed(methods) => {
+                write!(f, "{} not allowed for this key", describe_methods(methods))
+            }
+        }
+    }
+}
+
+impl KeyAccessError {
+    /// Disallowed methods are reported as JSON-RPC error, like nodes do for unknown methods.
+    /// Allowed calls of a rejected batch are not executed and get an error saying so.
+    pub fn to_response(
+        &self,
+        requests: &[ParsedRequest],
+        body_json: &serde_json::Value,
+    ) -> HttpResponse {
+        match self {
+            KeyAccessError::UnknownKey | KeyAccessError::InvalidSecret => {
+                HttpResponse::Unauthorized().body(self.to_string())
+            }
+            KeyAccessError::Expired(_) => HttpResponse::Forbidden().body(self.to_string()),
+            KeyAccessError::MethodNotAllowed(methods) => {
+                let body = rpc_error_response(requests, body_json, |req| {
+                    let message = match req {
+                        Some(req)
+                            if req.parse_error.is_none() && !methods.contains(&req.method) =>
+                        {
+                            "Not executed, batch contains methods not allowed for this key"
+                                .to_string()
+                        }
+                        Some(req) => format!("Method {} is not allowed for this key", req.method),
+                        None => self.to_string(),
+                    };
+                    SimulatedRpcError::new(METHOD_NOT_ALLOWED_ERROR_CODE, &message)
+                });
+                HttpResponse::Ok().json(body)
+            }
+        }
+    }
+}
+
+fn describe_methods(methods: &[String]) -> String {
+    match methods {
+        [method] => format!("Method {method} is"),
+        _ => format!("Methods {} are", methods.join(", ")),
+    }
+}
+
+/// Secret sent as `Authorization: Bearer <secret>` or as `secret` query parameter
+fn request_secret(req: &HttpRequest) -> Option<String> {
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, secret)| secret.trim().to_string());
+    bearer.or_else(|| {
+        web::Query::<HashMap<String, String>>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.get("secret").cloned())
+    })
+}
+
+/// Checks if the key can be used, before any call of the key is handled
+pub fn check_key(config: &ProxyConfig, key: &str, req: &HttpRequest) -> Result<(), KeyAccessError> {
+    let Some(key_config) = config.keys.get(key) else {
+        if config.registered_keys_only {
+            return Err(KeyAccessError::UnknownKey);
+        }
+        return Ok(());
+    };
+    if let Some(secret) = &key_config.secret {
+        let valid = request_secret(req)
+            .map(|sent| secrets_equal(secret, &sent))
+            .unwrap_or(false);
+        if !valid {
+            return Err(KeyAccessError::InvalidSecret);
+        }
+    }
+    if let Some(expires) = key_config.expires {
+        if expires <= chrono::Utc::now() {
+            return Err(KeyAccessError::Expired(expires));
+        }
+    }
+    Ok(())
+}
+
+/// Name used for calls whose method is unknown
+const UNPARSED_METHOD: &str = "<unparsed>";
+
+/// Methods the key can call, deny list takes precedence over allow list
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MethodAccess {
+    /// Method names or globs the key can call, any method if empty
+    pub allow: Vec<String>,
+    /// Method names or globs the key cannot call, e.g. `debug_*` or `eth_sendTransaction`
+    pub deny: Vec<String>,
+}
+
+impl MethodAccess {
+    pub fn is_restricted(&self) -> bool {
+        !self.allow.is_empty() || !self.deny.is_empty()
+    }
+
+    pub fn allows(&self, method: &str) -> bool {
+        !self
+            .deny
+            .iter()
+            .any(|pattern| method_matches(pattern, method))
+            && (self.allow.is_empty()
+                || self
+                    .allow
+                    .iter()
+                    .any(|pattern| method_matches(pattern, method)))
+    }
+
+    /// Checks methods of the request. If the key has restrictions,
+    /// calls that could not be parsed are rejected, because their methods are unknown.
+    pub fn check(&self, requests: &[ParsedRequest]) -> Result<(), KeyAccessError> {
+        if !self.is_restricted() {
+            return Ok(());
+        }
+        if requests.is_empty() {
+            return Err(KeyAccessError::MethodNotAllowed(vec![
+                UNPARSED_METHOD.to_string()
+            ]));
+        }
+        let mut denied: Vec<String> = Vec::new();
+        for req in requests {
+            let method = if req.parse_error.is_some() {
+                UNPARSED_METHOD
+            } else if !self.allows(&req.method) {
+                req.method.as_str()
+            } else {
+                continue;
+            };
+            if !denied.iter().any(|denied| denied == method) {
+                denied.push(method.to_string());
+            }
+        }
+        if denied.is_empty() {
+            Ok(())
+        } else {
+            Err(KeyAccessError::MethodNotAllowed(denied))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::MessageBody;
+    use actix_web::test::TestRequest;
+
+    fn access(allow: &[&str], deny: &[&str]) -> MethodAccess {
+        MethodAccess {
+            allow: allow.iter().map(|method| method.to_string()).collect(),
+            deny: deny.iter().map(|method| method.to_string()).collect(),
+        }
+    }
+
+    fn request(id: u64, method: &str) -> ParsedRequest {
+        ParsedRequest::new(serde_json::json!(id), method, vec![])
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let access = access(&["eth_*"], &["eth_sendTransaction"]);
+        assert!(access.allows("eth_call"));
+        assert!(!access.allows("eth_sendTransaction"));
+        assert!(!access.allows("debug_traceTransaction"));
+        assert!(MethodAccess::default().allows("debug_traceTransaction"));
+    }
+
+    #[test]
+    fn check_lists_every_denied_method_once() {
+        let access = access(&[], &["debug_*", "eth_sendTransaction"]);
+        let requests = [
+            request(1, "debug_traceCall"),
+            request(2, "eth_call"),
+            request(3, "eth_sendTransaction"),
+            request(4, "debug_traceCall"),
+        ];
+        match access.check(&requests) {
+            Err(KeyAccessError::MethodNotAllowed(methods)) => {
+                assert_eq!(methods, ["debug_traceCall", "eth_sendTransaction"])
+            }
+            other => panic!("unexpected result {other:?}"),
+        }
+        assert!(access.check(&requests[1..2]).is_ok());
+    }
+
+    #[test]
+    fn check_rejects_unparsed_calls_only_when_restricted() {
+        let mut unparsed = request(1, "eth_call");
+        unparsed.parse_error = Some("params field is missing".to_string());
+        let requests = [unparsed];
+        assert!(MethodAccess::default().check(&requests).is_ok());
+        assert!(MethodAccess::default().check(&[]).is_ok());
+        let access = access(&["eth_call"], &[]);
+        assert!(matches!(
+            access.check(&requests),
+            Err(KeyAccessError::MethodNotAllowed(methods)) if methods == [UNPARSED_METHOD]
+        ));
+        assert!(access.check(&[]).is_err());
+    }
+
+    #[test]
+    fn batch_errors_name_the_method_of_each_element() {
+        let access = access(&[], &["eth_sendTransaction"]);
+        let requests = [request(1, "eth_call"), request(2, "eth_sendTransaction")];
+        let body_json = serde_json::json!([{}, {}]);
+        let err = access.check(&requests).unwrap_err();
+        let response = err.to_response(&requests, &body_json);
+        let body = response.into_body().try_into_bytes().unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body[0]["id"], 1);
+        assert_eq!(
+            body[0]["error"]["message"],
+            "Not executed, batch contains methods not allowed for this key"
+        );
+        assert_eq!(body[1]["id"], 2);
+        assert_eq!(body[1]["error"]["code"], METHOD_NOT_ALLOWED_ERROR_CODE);
+        assert_eq!(
+            body[1]["error"]["message"],
+            "Method eth_sendTransaction is not allowed for this key"
+        );
+    }
+
+    const CONFIG: &str = r#"
+        registered_keys_only = true
+
+        [keys.open]
+
+        [keys.locked]
+        secret = "s3cret"
+
+        [keys.old]
+        expires = "2020-01-01T00:00:00Z"
+    "#;
+
+    #[test]
+    fn check_key_rejects_unknown_keys_when_registered_only() {
+        let config = ProxyConfig::from_toml(CONFIG);
+        let req = TestRequest::default().to_http_request();
+        assert!(check_key(&config, "open", &req).is_ok());
+        assert!(matches!(
+            check_key(&config, "other", &req),
+            Err(KeyAccessError::UnknownKey)
+        ));
+        let config = ProxyConfig::from_toml("");
+        assert!(check_key(&config, "other", &req).is_ok());
+    }
+
+    #[test]
+    fn check_key_requires_secret() {
+        let config = ProxyConfig::from_toml(CONFIG);
+        let missing = TestRequest::default().to_http_request();
+        let wrong = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Bearer other"))
+            .to_http_request();
+        let bearer = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "bearer s3cret"))
+            .to_http_request();
+        let query = TestRequest::with_uri("/web3/locked?secret=s3cret").to_http_request();
+        for req in [missing, wrong] {
+            assert!(matches!(
+                check_key(&config, "locked", &req),
+                Err(KeyAccessError::InvalidSecret)
+            ));
+        }
+        for req in [bearer, query] {
+            assert!(check_key(&config, "locked", &req).is_ok());
+        }
+    }
+
+    #[test]
+    fn check_key_rejects_expired_keys() {
+        let config = ProxyConfig::from_toml(CONFIG);
+        let req = TestRequest::default().to_http_request();
+        assert!(matches!(
+            check_key(&config, "old", &req),
+            Err(KeyAccessError::Expired(_))
+        ));
+    }
+}
@@ -1,6 +1,17 @@
+mod admin_auth;
+mod cache;
+mod coalesce;
+mod config;
 mod error;
 mod frontend;
+mod key_access;
+mod method_pattern;
 mod problems;
+mod rate_limit;
+mod simulated_body;
+mod storage;
+mod stored_body;
+mod upstream;
 
 extern crate core;
 
@@ -10,58 +21,95 @@ use actix_web::web::{Bytes, Data};
 use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder, Scope};
 use env_logger::Env;
 use rand::Rng;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::cmp::min;
 use std::collections::{HashMap, VecDeque};
 use std::fmt::Debug;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
+use crate::admin_auth::AdminAuth;
+use crate::cache::{CacheStatus, ResponseCache};
+use crate::coalesce::{InFlight, InFlightRequests, SharedResponse};
+use crate::config::{KeyConfig, ProxyConfig};
 use crate::frontend::{frontend_serve, redirect_to_frontend};
-use crate::problems::EndpointSimulateProblems;
-use tokio::sync::Mutex;
+use crate::key_access::{check_key, MethodAccess};
+use crate::problems::{
+    apply_batch_problems, rpc_error_response, EndpointSimulateProblems, ThrottleWindow, TimeoutMode,
+};
+use crate::rate_limit::{throttled_response, RateLimited, RateLimiter};
+use crate::simulated_body::{BrokenBody, StalledBody};
+use crate::storage::HistoryStorage;
+use crate::stored_body::{truncate_body, HistoryLimits, StoredBody};
+use crate::upstream::{
+    build_upstream_client, forward_to_upstreams, ForwardOutcome, KeyRouting, UpstreamAttempt,
+    UpstreamPool,
+};
+use tokio::sync::{Mutex, RwLock};
 
 #[derive(Debug, StructOpt, Clone)]
 pub struct CliOptions {
     #[structopt(long = "http", help = "Enable http server")]
     pub http: bool,
 
+    #[structopt(
+        long = "config",
+        env = "WEB3_PROXY_CONFIG",
+        help = "Path to TOML config file, options given on the command line take precedence"
+    )]
+    pub config: Option<PathBuf>,
+
     #[structopt(
         long = "http-threads",
-        help = "Number of threads to use for the server",
-        default_value = "2"
+        env = "WEB3_PROXY_HTTP_THREADS",
+        help = "Number of threads to use for the server [default: 2]"
     )]
-    pub http_threads: u64,
+    pub http_threads: Option<u64>,
 
     #[structopt(
         long = "http-port",
-        help = "Port number of the server",
-        default_value = "8080"
+        env = "WEB3_PROXY_HTTP_PORT",
+        help = "Port number of the server [default: 8080]"
     )]
-    pub http_port: u16,
+    pub http_port: Option<u16>,
 
     #[structopt(
         long = "http-addr",
-        help = "Bind address of the server",
-        default_value = "127.0.0.1"
+        env = "WEB3_PROXY_HTTP_ADDR",
+        help = "Bind address of the server [default: 127.0.0.1]"
     )]
-    pub http_addr: String,
+    pub http_addr: Option<String>,
 
     #[structopt(
         long = "target-addr",
-        help = "Target address of the server",
-        default_value = "http://polygongas.org:8545"
+        env = "WEB3_PROXY_TARGET_ADDR",
+        help = "Target address of the server, replaces upstreams from config file [default: http://polygongas.org:8545]"
     )]
-    pub target_addr: String,
+    pub target_addr: Option<String>,
 
     #[structopt(
         long = "queue-size",
-        help = "How many historical requests to keep",
-        default_value = "10000"
+        env = "WEB3_PROXY_QUEUE_SIZE",
+        help = "How many historical requests to keep [default: 10000]"
+    )]
+    pub request_queue_size: Option<usize>,
+
+    #[structopt(
+        long = "history-dir",
+        env = "WEB3_PROXY_HISTORY_DIR",
+        help = "Directory where call history and problems are stored, kept only in memory if not set"
     )]
-    pub request_queue_size: usize,
+    pub history_dir: Option<PathBuf>,
+
+    #[structopt(
+        long = "admin-token",
+        env = "WEB3_PROXY_ADMIN_TOKEN",
+        help = "Bearer token with read-write access to the admin API, in addition to the ones from config file"
+    )]
+    pub admin_token: Option<String>,
 }
 macro_rules! return_on_error_json {
     ( $e:expr ) => {
@@ -89,7 +137,7 @@ macro_rules! return_on_error_resp {
     };
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ParsedEthCallRequest {
     pub method: String,
@@ -97,13 +145,32 @@ pub struct ParsedEthCallRequest {
     pub to: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ParsedRequest {
     pub id: serde_json::Value,
     pub method: String,
     pub parsed_call: Option<ParsedEthCallRequest>,
     pub params: Vec<serde_json::Value>,
+    pub parse_error: Option<String>,
+}
+
+#[cfg(test)]
+impl ParsedRequest {
+    /// Successfully parsed call, used by tests
+    pub fn new(
+        id: serde_json::Value,
+        method: &str,
+        params: Vec<serde_json::Value>,
+    ) -> ParsedRequest {
+        ParsedRequest {
+            id,
+            method: method.to_string(),
+            parsed_call: None,
+            params,
+            parse_error: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -112,79 +179,167 @@ pub struct MethodInfo {
     pub id: String,
     pub method: String,
     pub parsed_call: Option<ParsedEthCallRequest>,
+    pub parse_error: Option<String>,
+    pub batch_index: Option<usize>,
     pub date: chrono::DateTime<chrono::Utc>,
     pub response_time: f64,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CallInfo {
     pub id: u64,
-    pub request: Option<String>,
-    pub response: Option<String>,
+    pub request: Option<StoredBody>,
+    pub response: Option<StoredBody>,
+    /// Size of the request before it was truncated, not set if the request is complete
+    #[serde(default)]
+    pub original_request_size: Option<usize>,
+    /// Size of the response before it was truncated, not set if the response is complete
+    #[serde(default)]
+    pub original_response_size: Option<usize>,
 
     pub parsed_request: Vec<ParsedRequest>,
     pub date: chrono::DateTime<chrono::Utc>,
     pub response_time: f64,
     pub status_code: u16,
+    /// Name of the upstream that produced the response
+    pub upstream: Option<String>,
+    /// Every call made to upstreams, including retries and failovers
+    #[serde(default)]
+    pub attempts: Vec<UpstreamAttempt>,
+    /// Set only if the request could be answered from the response cache
+    #[serde(default)]
+    pub cache: Option<CacheStatus>,
+    /// Response was shared with identical request that was already in flight
+    #[serde(default)]
+    pub coalesced: bool,
 }
 
-pub fn parse_request(
-    parsed_body: &serde_json::Value,
-) -> Result<Vec<ParsedRequest>, Web3ProxyError> {
-    let mut parsed_requests = Vec::new();
+impl CallInfo {
+    /// Approximate memory used by the call, including parsed params
+    pub fn memory_size(&self) -> usize {
+        std::mem::size_of::<CallInfo>()
+            + self
+                .request
+                .as_ref()
+                .map(StoredBody::memory_size)
+                .unwrap_or(0)
+            + self
+                .response
+                .as_ref()
+                .map(StoredBody::memory_size)
+                .unwrap_or(0)
+            + self
+                .parsed_request
+                .iter()
+                .map(|req| {
+                    std::mem::size_of::<ParsedRequest>()
+                        + req.method.len()
+                        + req
+                            .params
+                            .iter()
+                            .map(|param| param.to_string().len())
+                            .sum::<usize>()
+                })
+                .sum::<usize>()
+    }
 
-    if parsed_body.is_array() {
-    } else {
-        let jsonrpc = parsed_body["jsonrpc"]
-            .as_str()
-            .ok_or(err_custom_create!("jsonrpc field is missing"))?;
-        if jsonrpc != "2.0" {
-            return Err(err_custom_create!("jsonrpc field is not 2.0"));
-        }
-        let rpc_id = parsed_body["id"].clone();
-        let method = parsed_body["method"]
-            .as_str()
-            .ok_or(err_custom_create!("method field is missing"))?;
-        let params = parsed_body["params"]
-            .as_array()
-            .ok_or(err_custom_create!("params field is missing"))?;
-        let mut parsed_call = None;
-        if method == "eth_getBalance" {
-            if params.is_empty() {
-                return Err(err_custom_create!("params field is empty"));
-            }
-            parsed_call = Some(ParsedEthCallRequest {
-                to: None,
-                method: "get_balance".to_string(),
-                address: Some(params[0].as_str().unwrap().to_string()),
-            });
-        } else if method == "eth_call" {
-            if params.is_empty() {
-                return Err(err_custom_create!("params field is empty"));
-            }
-            if let Some(obj) = params[0].as_object() {
-                if let Some(data) = obj.get("data").and_then(|x| x.as_str()) {
-                    let data: String = data.to_lowercase();
-                    if (data.len() == 74) && (data.starts_with("0x70a08231")) {
-                        parsed_call = Some(ParsedEthCallRequest {
-                            to: params[0]["to"].as_str().map(|x| x.to_string()),
-                            method: "balanceOf".to_string(),
-                            address: Some(format!("0x{}", data.split_at(34).1)),
-                        });
-                    }
+    /// Truncates and compresses bodies according to history limits
+    pub fn apply_limits(&mut self, limits: &HistoryLimits) {
+        if let Some(request) = self.request.take() {
+            let (request, original_size) = truncate_body(request.into_text(), limits.max_body_size);
+            self.request = Some(StoredBody::new(request, limits.compress_bodies));
+            self.original_request_size = self.original_request_size.or(original_size);
+        }
+        if let Some(response) = self.response.take() {
+            let (response, original_size) =
+                truncate_body(response.into_text(), limits.max_body_size);
+            self.response = Some(StoredBody::new(response, limits.compress_bodies));
+            self.original_response_size = self.original_response_size.or(original_size);
+        }
+    }
+}
+
+fn parse_single_request(parsed_body: &serde_json::Value) -> Result<ParsedRequest, Web3ProxyError> {
+    let jsonrpc = parsed_body["jsonrpc"]
+        .as_str()
+        .ok_or(err_custom_create!("jsonrpc field is missing"))?;
+    if jsonrpc != "2.0" {
+        return Err(err_custom_create!("jsonrpc field is not 2.0"));
+    }
+    let rpc_id = parsed_body["id"].clone();
+    let method = parsed_body["method"]
+        .as_str()
+        .ok_or(err_custom_create!("method field is missing"))?;
+    let params = parsed_body["params"]
+        .as_array()
+        .ok_or(err_custom_create!("params field is missing"))?;
+    let mut parsed_call = None;
+    if method == "eth_getBalance" {
+        if params.is_empty() {
+            return Err(err_custom_create!("params field is empty"));
+        }
+        parsed_call = Some(ParsedEthCallRequest {
+            to: None,
+            method: "get_balance".to_string(),
+            address: Some(
+                params[0]
+                    .as_str()
+                    .ok_or(err_custom_create!("address param is not a string"))?
+                    .to_string(),
+            ),
+        });
+    } else if method == "eth_call" {
+        if params.is_empty() {
+            return Err(err_custom_create!("params field is empty"));
+        }
+        if let Some(obj) = params[0].as_object() {
+            if let Some(data) = obj.get("data").and_then(|x| x.as_str()) {
+                let data: String = data.to_lowercase();
+                if (data.len() == 74) && (data.starts_with("0x70a08231")) {
+                    parsed_call = Some(ParsedEthCallRequest {
+                        to: params[0]["to"].as_str().map(|x| x.to_string()),
+                        method: "balanceOf".to_string(),
+                        address: Some(format!("0x{}", data.split_at(34).1)),
+                    });
                 }
             }
         }
+    }
 
-        parsed_requests.push(ParsedRequest {
-            id: rpc_id,
-            method: method.to_string(),
-            parsed_call,
-            params: params.clone(),
-        });
+    Ok(ParsedRequest {
+        id: rpc_id,
+        method: method.to_string(),
+        parsed_call,
+        params: params.clone(),
+        parse_error: None,
+    })
+}
+
+pub fn parse_request(
+    parsed_body: &serde_json::Value,
+) -> Result<Vec<ParsedRequest>, Web3ProxyError> {
+    if let Some(batch) = parsed_body.as_array() {
+        if batch.is_empty() {
+            return Err(err_custom_create!("batch request is empty"));
+        }
+        // Elements of the batch are parsed independently, so one malformed call
+        // does not hide the rest of the batch from the history.
+        Ok(batch
+            .iter()
+            .map(|element| {
+                parse_single_request(element).unwrap_or_else(|err| ParsedRequest {
+                    id: element["id"].clone(),
+                    method: element["method"].as_str().unwrap_or_default().to_string(),
+                    parsed_call: None,
+                    params: element["params"].as_array().cloned().unwrap_or_default(),
+                    parse_error: Some(err.inner.to_string()),
+                })
+            })
+            .collect())
+    } else {
+        Ok(vec![parse_single_request(parsed_body)?])
     }
-    Ok(parsed_requests)
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -197,15 +352,308 @@ pub struct KeyData {
 
     pub calls: VecDeque<CallInfo>,
     pub problems: EndpointSimulateProblems,
+    pub routing: KeyRouting,
+    pub method_access: MethodAccess,
+    #[serde(skip)]
+    pub throttle_window: ThrottleWindow,
+    /// Memory used by calls, see CallInfo::memory_size
+    #[serde(skip)]
+    pub memory_used: usize,
+    /// Date of the last request, including rejected ones. Kept apart from calls,
+    /// because history can be cleared by memory limit while the key is in use.
+    #[serde(skip)]
+    pub last_activity: chrono::DateTime<chrono::Utc>,
+}
+
+impl KeyData {
+    pub fn new(key: &str, problems: EndpointSimulateProblems) -> KeyData {
+        KeyData {
+            key: key.to_string(),
+            value: "1".to_string(),
+            total_calls: 0,
+            total_requests: 0,
+            calls: VecDeque::new(),
+            problems,
+            routing: KeyRouting::default(),
+            method_access: MethodAccess::default(),
+            throttle_window: ThrottleWindow::default(),
+            memory_used: 0,
+            last_activity: chrono::Utc::now(),
+        }
+    }
+
+    /// Adds the call with next id and returns the id, oldest calls over the queue size are removed
+    fn push_call(&mut self, mut call_info: CallInfo, queue_size: usize) -> u64 {
+        let call_id = self.total_calls;
+        call_info.id = call_id;
+        self.total_calls += 1;
+        self.last_activity = self.last_activity.max(call_info.date);
+        self.memory_used += call_info.memory_size();
+        self.calls.push_back(call_info);
+        self.trim_calls(queue_size);
+        call_id
+    }
+
+    fn trim_calls(&mut self, queue_size: usize) {
+        while self.calls.len() > queue_size {
+            self.pop_oldest_call();
+        }
+    }
+
+    fn pop_oldest_call(&mut self) -> Option<CallInfo> {
+        let call_info = self.calls.pop_front()?;
+        self.memory_used = self.memory_used.saturating_sub(call_info.memory_size());
+        Some(call_info)
+    }
+
+    pub fn set_calls(&mut self, calls: VecDeque<CallInfo>) {
+        if let Some(call_info) = calls.back() {
+            self.last_activity = call_info.date;
+        }
+        self.memory_used = calls.iter().map(CallInfo::memory_size).sum();
+        self.calls = calls;
+    }
+
+    fn clear_calls(&mut self) {
+        self.calls.clear();
+        self.memory_used = 0;
+    }
+
+    pub fn idle_time(&self, now: chrono::DateTime<chrono::Utc>) -> chrono::Duration {
+        now - self.last_activity
+    }
+
+    pub fn apply_config(&mut self, key_config: &KeyConfig) {
+        self.problems = key_config.problems.clone();
+        self.routing = key_config.routing();
+        self.method_access = key_config.method_access();
+    }
 }
 
+/// Call history of all keys. History is changed through methods of SharedData,
+/// so the memory used by all keys is counted without going over every key.
 pub struct SharedData {
     pub keys: HashMap<String, KeyData>,
+    memory_used: usize,
 }
 
+impl SharedData {
+    pub fn new(keys: HashMap<String, KeyData>) -> SharedData {
+        let mut shared_data = SharedData {
+            keys,
+            memory_used: 0,
+        };
+        shared_data.recount_memory();
+        shared_data
+    }
+
+    /// Data of the key, new keys get settings from the config file.
+    /// Keys can be created again after their history is deleted, so config is applied here
+    /// and not only on startup.
+    pub fn key_data_mut(&mut self, key: &str, config: &ProxyConfig) -> &mut KeyData {
+        self.keys.entry(key.to_string()).or_insert_with(|| {
+            let mut key_data = KeyData::new(key, EndpointSimulateProblems::default());
+            if let Some(key_config) = config.keys.get(key) {
+                key_data.apply_config(key_config);
+            }
+            key_data
+        })
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.memory_used
+    }
+
+    fn recount_memory(&mut self) {
+        self.memory_used = self
+            .keys
+            .values()
+            .map(|key_data| key_data.memory_used)
+            .sum();
+    }
+
+    /// Adds the call to the history of existing key, returns id of the call
+    pub fn push_call(&mut self, key: &str, call_info: CallInfo, queue_size: usize) -> Option<u64> {
+        let key_data = self.keys.get_mut(key)?;
+        let memory_before = key_data.memory_used;
+        let call_id = key_data.push_call(call_info, queue_size);
+        self.memory_used = self.memory_used - memory_before + key_data.memory_used;
+        Some(call_id)
+    }
+
+    pub fn trim_calls(&mut self, queue_size: usize) {
+        for key_data in self.keys.values_mut() {
+            key_data.trim_calls(queue_size);
+        }
+        self.recount_memory();
+    }
+
+    pub fn remove_key(&mut self, key: &str) -> Option<KeyData> {
+        let key_data = self.keys.remove(key)?;
+        self.memory_used -= key_data.memory_used;
+        Some(key_data)
+    }
+
+    pub fn remove_all_keys(&mut self) {
+        self.keys.clear();
+        self.memory_used = 0;
+    }
+
+    /// Clears history of least recently active keys until the memory is within the limit.
+    /// History of the active key is removed last, starting from its oldest calls.
+    pub fn enforce_memory_limit(&mut self, memory_limit: usize, active_key: &str) {
+        if self.memory_used <= memory_limit {
+            return;
+        }
+        let mut oldest_keys: Vec<(chrono::DateTime<chrono::Utc>, String)> = self
+            .keys
+            .values()
+            .filter(|key_data| key_data.key != active_key && !key_data.calls.is_empty())
+            .map(|key_data| (key_data.last_activity, key_data.key.clone()))
+            .collect();
+        oldest_keys.sort_unstable();
+        for (_, oldest_key) in oldest_keys {
+            if self.memory_used <= memory_limit {
+                return;
+            }
+            if let Some(key_data) = self.keys.get_mut(&oldest_key) {
+                log::info!(
+                    "History memory limit reached, removing {} calls of key {}",
+                    key_data.calls.len(),
+                    oldest_key
+                );
+                self.memory_used -= key_data.memory_used;
+                key_data.clear_calls();
+            }
+        }
+        if let Some(key_data) = self.keys.get_mut(active_key) {
+            let memory_before = key_data.memory_used;
+            let other_keys = self.memory_used - memory_before;
+            // the newest call is always kept
+            while other_keys + key_data.memory_used > memory_limit && key_data.calls.len() > 1 {
+                key_data.pop_oldest_call();
+            }
+            self.memory_used = other_keys + key_data.memory_used;
+        }
+    }
+}
+
+/// Created for every worker, so the upstream client and its connection pool are reused
+/// by all requests of the worker. Remaining fields are shared between workers.
 pub struct ServerData {
-    pub options: CliOptions,
+    pub cli: CliOptions,
+    pub config: Arc<RwLock<ProxyConfig>>,
     pub shared_data: Arc<Mutex<SharedData>>,
+    pub upstreams: Arc<Mutex<UpstreamPool>>,
+    pub cache: Arc<Mutex<ResponseCache>>,
+    pub in_flight: Arc<InFlightRequests>,
+    pub rate_limiter: Arc<Mutex<RateLimiter>>,
+    pub storage: Option<Arc<HistoryStorage>>,
+    pub client: awc::Client,
+}
+
+impl ServerData {
+    /// Loads config file again and applies it without touching the history.
+    /// Listen address, number of threads and history directory require restart.
+    pub async fn reload_config(&self) -> Result<(), Web3ProxyError> {
+        let mut new_config = ProxyConfig::load(&self.cli)?;
+        let mut config = self.config.write().await;
+        if new_config.http_addr != config.http_addr
+            || new_config.http_port != config.http_port
+            || new_config.http_threads != config.http_threads
+            || new_config.history_dir != config.history_dir
+            || new_config.upstream_client != config.upstream_client
+            || new_config.admin.cors_allowed_origins != config.admin.cors_allowed_origins
+        {
+            log::warn!(
+                "Changes of listen address, threads, history dir, upstream client or CORS origins require restart"
+            );
+        }
+        new_config.http_addr = config.http_addr.clone();
+        new_config.http_port = config.http_port;
+        new_config.http_threads = config.http_threads;
+        new_config.history_dir = config.history_dir.clone();
+        new_config.upstream_client = config.upstream_client.clone();
+        new_config.admin.cors_allowed_origins = config.admin.cors_allowed_origins.clone();
+
+        let mut shared_data = self.shared_data.lock().await;
+        for (key, key_config) in &new_config.keys {
+            shared_data
+                .keys
+                .entry(key.clone())
+                .or_insert_with(|| KeyData::new(key, EndpointSimulateProblems::default()))
+                .apply_config(key_config);
+        }
+        shared_data.trim_calls(new_config.request_queue_size);
+
+        self.upstreams.lock().await.update(
+            &new_config.upstreams,
+            new_config.upstream_failure_threshold,
+            new_config.upstream_cooldown,
+        );
+        self.cache.lock().await.update(new_config.cache.clone());
+
+        *config = new_config;
+        log::info!("Config reloaded");
+        Ok(())
+    }
+
+    /// Removes keys without traffic for longer than `idle_ttl`, together with their stored
+    /// history and settings. Keys defined in the config file are never removed.
+    /// Runs the file operation on the blocking thread pool. Callers release their locks
+    /// before, so requests of other keys are not held up by the disk.
+    pub async fn run_storage(
+        &self,
+        op: impl FnOnce(&HistoryStorage) -> Result<(), Web3ProxyError> + Send + 'static,
+    ) -> Result<(), Web3ProxyError> {
+        let Some(storage) = self.storage.clone() else {
+            return Ok(());
+        };
+        web::block(move || op(&storage))
+            .await
+            .map_err(|e| err_custom_create!("Storage task failed: {e}"))?
+    }
+
+    pub async fn remove_idle_keys(&self, idle_ttl: Duration) {
+        let Ok(idle_ttl) = chrono::Duration::from_std(idle_ttl) else {
+            return;
+        };
+        let config = self.config.read().await;
+        let mut shared_data = self.shared_data.lock().await;
+        let now = chrono::Utc::now();
+        let idle_keys: Vec<String> = shared_data
+            .keys
+            .values()
+            .filter(|key_data| !config.keys.contains_key(&key_data.key))
+            .filter(|key_data| key_data.idle_time(now) > idle_ttl)
+            .map(|key_data| key_data.key.clone())
+            .collect();
+        if idle_keys.is_empty() {
+            return;
+        }
+        let mut rate_limiter = self.rate_limiter.lock().await;
+        for key in &idle_keys {
+            shared_data.remove_key(key);
+            rate_limiter.remove_key(key);
+        }
+        drop(rate_limiter);
+        drop(shared_data);
+        drop(config);
+        for key in &idle_keys {
+            let stored_key = key.clone();
+            let removed = self
+                .run_storage(move |storage| storage.remove_key(&stored_key))
+                .await;
+            if let Err(err) = removed {
+                log::error!("Error removing stored data of idle key {key}: {err}");
+            }
+        }
+        log::info!(
+            "Removed {} keys idle for more than {}s",
+            idle_keys.len(),
+            idle_ttl.num_seconds()
+        );
+    }
 }
 
 pub async fn get_calls(req: HttpRequest, server_data: Data<Box<ServerData>>) -> impl Responder {
@@ -244,12 +692,16 @@ pub async fn get_methods(req: HttpRequest, server_data: Data<Box<ServerData>>) -
     let methods = calls
         .iter()
         .flat_map(|call| {
+            let is_batch = call.parsed_request.len() > 1;
             call.parsed_request
                 .iter()
-                .map(|req| MethodInfo {
+                .enumerate()
+                .map(|(idx, req)| MethodInfo {
                     id: req.id.to_string(),
                     method: req.method.clone(),
                     parsed_call: req.parsed_call.clone(),
+                    parse_error: req.parse_error.clone(),
+                    batch_index: is_batch.then_some(idx),
                     date: call.date,
                     response_time: call.response_time,
                 })
@@ -270,47 +722,72 @@ pub async fn web3(
     let body_str = return_on_error_resp!(String::from_utf8(body.to_vec()));
     let body_json: serde_json::Value = return_on_error_resp!(serde_json::from_str(&body_str));
 
+    // Rejected keys are not created, so unknown keys cannot fill the memory
+    if let Err(err) = check_key(&*server_data.config.read().await, key, &req) {
+        log::warn!("Key {} rejected: {}", key, err);
+        return err.to_response(&[], &body_json);
+    }
+
     // Before call check.
     // Obtain lock and check conditions if we should call the function.
-    let problems = {
+    let (key_problems, key_routing, method_access) = {
+        let config = server_data.config.read().await;
         let mut shared_data = server_data.shared_data.lock().await;
-        let key_data = shared_data.keys.get_mut(key);
-
-        if let Some(key_data) = key_data {
+        let is_new_key = !shared_data.keys.contains_key(key);
+        let key_data = shared_data.key_data_mut(key, &config);
+        key_data.last_activity = chrono::Utc::now();
+        if !is_new_key {
             key_data.value = "test".to_string();
             key_data.total_requests += 1;
-            key_data.problems.clone()
-        } else {
-            let key_data = KeyData {
-                key: key.to_string(),
-                value: "1".to_string(),
-                total_calls: 0,
-                total_requests: 0,
-                calls: VecDeque::new(),
-                problems: EndpointSimulateProblems::default(),
-            };
-            shared_data.keys.insert(key.to_string(), key_data);
-            EndpointSimulateProblems::default()
         }
+        (
+            key_data.problems.clone(),
+            key_data.routing.clone(),
+            key_data.method_access.clone(),
+        )
     };
     let parsed_request = match parse_request(&body_json) {
         Ok(parsed_request) => parsed_request,
         Err(e) => {
             log::error!("Error parsing request: {}", e);
-            if problems.allow_only_parsed_calls {
+            if key_problems.allow_only_parsed_calls {
                 return HttpResponse::BadRequest().body(e.to_string());
             }
             vec![]
         }
     };
-    if parsed_request.len() >= 2 && problems.allow_only_single_calls {
+    // Denied calls never reach the upstream
+    if let Err(err) = method_access.check(&parsed_request) {
+        log::warn!("Key {} rejected: {}", key, err);
+        return err.to_response(&parsed_request, &body_json);
+    }
+    // Request level problems are taken from the profile of the first method in the request,
+    // gates deciding which requests the key accepts are taken from the key itself
+    let problems = parsed_request
+        .first()
+        .map(|req| key_problems.for_method(&req.method))
+        .unwrap_or(&key_problems)
+        .clone();
+    if key_problems.allow_only_parsed_calls {
+        if let Some(err) = parsed_request
+            .iter()
+            .find_map(|req| req.parse_error.as_ref())
+        {
+            log::error!("Error parsing batch element: {}", err);
+            return HttpResponse::BadRequest().body(err.clone());
+        }
+    }
+    if parsed_request.len() >= 2 && key_problems.allow_only_single_calls {
         return HttpResponse::BadRequest().body("Only single rpc call allowed at once");
     }
 
     log::info!(
         "key: {}, method: {:?}",
         key,
-        parsed_request.get(0).map(|x| x.method.clone())
+        parsed_request
+            .iter()
+            .map(|x| x.method.as_str())
+            .collect::<Vec<&str>>()
     );
 
     //do the long call here
@@ -321,19 +798,103 @@ pub async fn web3(
     let mut rng = rand::thread_rng();
     let mut response_body_str = None;
 
-    let status_code = if problems.error_chance > 0.0
-        && rng.gen_range(0.0..1.0) < problems.error_chance
-    {
+    let rate_limited = {
+        let config = server_data.config.read().await;
+        let methods: Vec<&str> = parsed_request
+            .iter()
+            .map(|req| req.method.as_str())
+            .collect();
+        let key_limit = config
+            .keys
+            .get(key)
+            .and_then(|key_config| key_config.rate_limit.as_ref());
+        server_data
+            .rate_limiter
+            .lock()
+            .await
+            .check(&config.rate_limit, key, key_limit, &methods)
+            .err()
+            .map(|limited| (limited, config.rate_limit.response))
+    };
+    // window is counted per key, so the limit of the key is used for every method
+    let rate_limited = match (rate_limited, &key_problems.throttling) {
+        (None, Some(throttling)) => {
+            let mut shared_data = server_data.shared_data.lock().await;
+            shared_data
+                .keys
+                .get_mut(key)
+                .and_then(|key_data| key_data.throttle_window.hit(throttling, Instant::now()))
+                .map(|retry_after| {
+                    let limited = RateLimited {
+                        scope: "simulated throttling".to_string(),
+                        retry_after,
+                    };
+                    (limited, throttling.response)
+                })
+        }
+        (rate_limited, _) => rate_limited,
+    };
+
+    if let Some(latency) = problems.latency.as_ref().filter(|_| rate_limited.is_none()) {
+        let delay = latency.sample(&mut rng);
+        log::info!("Simulated latency: {:.3}s", delay.as_secs_f64());
+        tokio::time::sleep(delay).await;
+    }
+
+    // Timeout modes that are not resolved before upstream call are handled after the call is recorded
+    let mut timeout_hit = None;
+    let mut used_upstream = None;
+    let mut upstream_attempts = Vec::new();
+    let mut cache_status = None;
+    let mut coalesced = false;
+
+    let status_code = if let Some((limited, response)) = &rate_limited {
+        log::info!(
+            "Rate limit of {} exceeded, retry after {:.3}s",
+            limited.scope,
+            limited.retry_after.as_secs_f64()
+        );
+        let (status, body) = throttled_response(*response, &parsed_request, &body_json);
+        response_body_str = Some(body);
+        status
+    } else if problems.error_chance > 0.0 && rng.gen_range(0.0..1.0) < problems.error_chance {
         log::info!("Error chance hit! ({}%)", problems.error_chance * 100.0);
         response_body_str = Some("simulated 500 error".to_string());
         StatusCode::INTERNAL_SERVER_ERROR
-    } else if problems.timeout_chance > 0.0 && rng.gen_range(0.0..1.0) < problems.timeout_chance {
-        log::info!("Timeout chance hit! ({}%)", problems.timeout_chance * 100.0);
-        tokio::time::sleep(Duration::from_secs(15)).await;
-        response_body_str = Some("simulated 500 error".to_string());
-        StatusCode::GATEWAY_TIMEOUT
+    } else if problems.timeout_chance > 0.0
+        && !matches!(problems.timeout_mode, TimeoutMode::LateResponse { .. })
+        && rng.gen_range(0.0..1.0) < problems.timeout_chance
+    {
+        log::info!(
+            "Timeout chance hit! ({}%), mode: {:?}",
+            problems.timeout_chance * 100.0,
+            problems.timeout_mode
+        );
+        timeout_hit = Some(problems.timeout_mode.clone());
+        match problems.timeout_mode {
+            TimeoutMode::GatewayTimeout { delay_ms } => {
+                tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+                response_body_str = Some("simulated 500 error".to_string());
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            TimeoutMode::StallBody => StatusCode::OK,
+            _ => StatusCode::GATEWAY_TIMEOUT,
+        }
+    } else if problems.rpc_error_chance > 0.0 && rng.gen_range(0.0..1.0) < problems.rpc_error_chance
+    {
+        log::info!(
+            "JSON-RPC error chance hit! ({}%)",
+            problems.rpc_error_chance * 100.0
+        );
+        response_body_str = Some(
+            rpc_error_response(&parsed_request, &body_json, |_| {
+                problems.random_rpc_error(&mut rng)
+            })
+            .to_string(),
+        );
+        StatusCode::OK
     } else if parsed_request
-        .get(0)
+        .first()
         .map(|f| f.method == "eth_sendRawTransaction")
         .unwrap_or(false)
         && problems.skip_sending_raw_transaction_chance > 0.0
@@ -349,64 +910,153 @@ pub async fn web3(
 
         response_body_str = Some(
             json!({"jsonrpc": "2.0",
-                "id": parsed_request.get(0).unwrap().id,
+                "id": parsed_request.first().unwrap().id,
                 "result": random_hash})
             .to_string(),
         );
         StatusCode::OK
     } else {
-        let client = awc::Client::new();
-        let res = client
-            .post(&server_data.options.target_addr)
-            .send_json(&body_json)
-            .await;
-        log::debug!("res: {:?}", res);
-
-        match res {
-            Ok(mut cr) => {
-                let body_res = cr.body().await;
-                match body_res {
-                    Ok(body) => match String::from_utf8(body.to_vec()) {
-                        Ok(body_str) => {
-                            if problems.send_transaction_but_report_failure_chance > 0.0
-                                && parsed_request
-                                    .get(0)
-                                    .map(|f| f.method == "eth_sendRawTransaction")
-                                    .unwrap_or(false)
-                                && rng.gen_range(0.0..1.0)
-                                    < problems.send_transaction_but_report_failure_chance
-                            {
-                                log::info!(
-                                    "Send raw transaction but report error hit! ({}%)",
-                                    problems.send_transaction_but_report_failure_chance * 100.0
-                                );
-                                StatusCode::from_u16(500).unwrap()
-                            }
-                            else if problems.malformed_response_chance > 0.0
-                                && rng.gen_range(0.0..1.0) < problems.malformed_response_chance
-                            {
-                                log::info!(
-                                    "Malformed response chance hit! ({}%)",
-                                    problems.malformed_response_chance * 100.0
-                                );
-                                response_body_str =
-                                    Some(body_str[0..body_str.len() / 2].to_string());
-                                cr.status()
-                            } else {
-                                //normal path return the response
-                                response_body_str = Some(body_str);
-                                cr.status()
-                            }
-                        }
-                        Err(err) => {
-                            log::error!("Error getting body: {:?}", err);
-                            StatusCode::from_u16(500).unwrap()
+        let cached = {
+            let mut cache = server_data.cache.lock().await;
+            if cache.is_cacheable(&parsed_request) {
+                let cached =
+                    cache.get_response(&key_routing, &parsed_request, body_json.is_array());
+                cache_status = Some(if cached.is_some() {
+                    CacheStatus::Hit
+                } else {
+                    CacheStatus::Miss
+                });
+                cached
+            } else {
+                None
+            }
+        };
+        let result = if let Some(cached) = cached {
+            log::debug!("Response served from cache");
+            Ok((StatusCode::OK, cached))
+        } else {
+            let (balance_strategy, method_route) = {
+                let config = server_data.config.read().await;
+                let methods: Vec<&str> = parsed_request
+                    .iter()
+                    .map(|req| req.method.as_str())
+                    .collect();
+                let method_route = config
+                    .routes
+                    .iter()
+                    .find(|route| route.matches(&key_routing, &methods))
+                    .cloned();
+                (config.balance_strategy, method_route)
+            };
+            if let Some(method_route) = &method_route {
+                log::debug!("Using route for method {}", method_route.method);
+            }
+            let candidates = server_data.upstreams.lock().await.candidates(
+                &key_routing,
+                method_route.as_ref(),
+                balance_strategy,
+            );
+            let (client_config, retry_policy, coalesce_requests) = {
+                let config = server_data.config.read().await;
+                (
+                    config.upstream_client.clone(),
+                    config.retry_policy.clone(),
+                    config.coalesce_requests,
+                )
+            };
+            let sends_transaction = parsed_request.iter().any(|req| {
+                req.method == "eth_sendRawTransaction" || req.method == "eth_sendTransaction"
+            });
+            let forward = || {
+                forward_to_upstreams(
+                    &server_data.client,
+                    &server_data.upstreams,
+                    candidates.clone(),
+                    &body_json,
+                    &client_config,
+                    &retry_policy,
+                    sends_transaction,
+                )
+            };
+            // Transactions are never shared, every client expects its own one to be sent
+            let in_flight = if coalesce_requests && !sends_transaction {
+                InFlightRequests::coalesce_key(&key_routing, &parsed_request, body_json.is_array())
+                    .map(|coalesce_key| server_data.in_flight.join(&coalesce_key))
+            } else {
+                None
+            };
+            let outcome = match in_flight {
+                Some(InFlight::Waiter(mut receiver)) => match receiver.recv().await {
+                    Ok(shared) => {
+                        log::debug!("Response shared with identical request in flight");
+                        coalesced = true;
+                        ForwardOutcome {
+                            result: shared.result_for(&parsed_request),
+                            upstream: shared.upstream.clone(),
+                            attempts: Vec::new(),
                         }
-                    },
-                    Err(e) => {
-                        log::error!("Error getting body: {:?}", e);
-                        StatusCode::from_u16(500).unwrap()
                     }
+                    // leading request was cancelled before getting the response
+                    Err(_) => forward().await,
+                },
+                Some(InFlight::Leader(leader)) => {
+                    let outcome = forward().await;
+                    leader.finish(SharedResponse {
+                        result: outcome.result.clone(),
+                        upstream: outcome.upstream.clone(),
+                        requests: parsed_request.clone(),
+                    });
+                    outcome
+                }
+                None => forward().await,
+            };
+            used_upstream = outcome.upstream;
+            upstream_attempts = outcome.attempts;
+            if let Ok((status, body_str)) = &outcome.result {
+                if *status == StatusCode::OK && cache_status.is_some() && !coalesced {
+                    server_data.cache.lock().await.store_response(
+                        &key_routing,
+                        &parsed_request,
+                        body_str,
+                    );
+                }
+            }
+            outcome.result
+        };
+
+        match result {
+            Ok((status, body_str)) => {
+                if problems.send_transaction_but_report_failure_chance > 0.0
+                    && parsed_request
+                        .first()
+                        .map(|f| f.method == "eth_sendRawTransaction")
+                        .unwrap_or(false)
+                    && rng.gen_range(0.0..1.0) < problems.send_transaction_but_report_failure_chance
+                {
+                    log::info!(
+                        "Send raw transaction but report error hit! ({}%)",
+                        problems.send_transaction_but_report_failure_chance * 100.0
+                    );
+                    StatusCode::from_u16(500).unwrap()
+                } else if problems.malformed_response_chance > 0.0
+                    && rng.gen_range(0.0..1.0) < problems.malformed_response_chance
+                {
+                    log::info!(
+                        "Malformed response chance hit! ({}%)",
+                        problems.malformed_response_chance * 100.0
+                    );
+                    response_body_str = Some(body_str[0..body_str.len() / 2].to_string());
+                    status
+                } else if body_json.is_array() && key_problems.has_batch_problems() {
+                    response_body_str = Some(
+                        apply_batch_problems(&key_problems, &parsed_request, &body_str, &mut rng)
+                            .unwrap_or(body_str),
+                    );
+                    status
+                } else {
+                    //normal path return the response
+                    response_body_str = Some(body_str);
+                    status
                 }
             }
             Err(err) => {
@@ -416,55 +1066,125 @@ pub async fn web3(
         }
     };
 
+    if let TimeoutMode::LateResponse { delay_ms } = problems.timeout_mode {
+        if rate_limited.is_none()
+            && problems.timeout_chance > 0.0
+            && rng.gen_range(0.0..1.0) < problems.timeout_chance
+        {
+            log::info!(
+                "Timeout chance hit! ({}%), delaying response by {}ms",
+                problems.timeout_chance * 100.0,
+                delay_ms
+            );
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+        }
+    }
+
     let finish = Instant::now();
     //After call update info
     {
         let mut call_info = CallInfo {
             id: 0,
             date: call_date,
-            request: Some(body_str),
+            request: Some(StoredBody::Plain(body_str)),
+            original_request_size: None,
             parsed_request,
-            response: response_body_str.clone(),
+            response: response_body_str.clone().map(StoredBody::Plain),
+            original_response_size: None,
             response_time: (finish - start).as_secs_f64(),
             status_code: status_code.as_u16(),
+            upstream: used_upstream,
+            attempts: upstream_attempts,
+            cache: cache_status,
+            coalesced,
         };
 
-        let mut shared_data = server_data.shared_data.lock().await;
-        let mut key_data = return_on_error_resp!(shared_data
-            .keys
-            .get_mut(key)
-            .ok_or("Key not found - something went really wrong, beacue it should be here"));
-        key_data.total_calls += 1;
-
-        if !key_data.calls.is_empty() {
-            call_info.id = key_data.calls.back().unwrap().id + 1;
+        let (request_queue_size, history_limits) = {
+            let config = server_data.config.read().await;
+            (config.request_queue_size, config.history_limits.clone())
+        };
+        call_info.apply_limits(&history_limits);
+        // copy for storage is made before taking the lock, it is written to disk after releasing it
+        let mut stored_call = server_data.storage.as_ref().map(|_| call_info.clone());
+        let call_id = {
+            let mut shared_data = server_data.shared_data.lock().await;
+            let call_id = return_on_error_resp!(shared_data
+                .push_call(key, call_info, request_queue_size)
+                .ok_or("Key not found - something went really wrong, beacue it should be here"));
+            shared_data.enforce_memory_limit(history_limits.memory_limit, key);
+            call_id
+        };
+        if let (Some(storage), Some(stored_call)) = (&server_data.storage, &mut stored_call) {
+            stored_call.id = call_id;
+            if let Err(err) = storage.append(key, stored_call) {
+                log::error!("Error storing call history: {}", err);
+            }
         }
-        key_data.calls.push_back(call_info);
-        if key_data.calls.len() > server_data.options.request_queue_size {
-            key_data.calls.pop_front();
+    }
+    match timeout_hit {
+        Some(TimeoutMode::Hang) => {
+            // actix does not drop the handler when the client disconnects,
+            // so the wait is bounded to release the socket eventually
+            tokio::time::sleep(Duration::from_millis(problems.hang_max_ms)).await;
+            return HttpResponse::build(status_code).body(BrokenBody);
+        }
+        Some(TimeoutMode::DropConnection) => {
+            return HttpResponse::build(status_code).body(BrokenBody);
+        }
+        Some(TimeoutMode::StallBody) => {
+            return HttpResponse::build(status_code).body(StalledBody::new(Duration::from_millis(
+                problems.hang_max_ms,
+            )));
+        }
+        _ => {}
+    }
+    let mut response = HttpResponse::build(status_code);
+    if status_code == StatusCode::TOO_MANY_REQUESTS {
+        if let Some((limited, _)) = &rate_limited {
+            response.insert_header(("Retry-After", limited.retry_after_secs().to_string()));
         }
     }
     if let Some(response_body_str) = response_body_str {
-        HttpResponse::build(status_code).body(response_body_str)
+        response.body(response_body_str)
     } else {
-        HttpResponse::build(status_code).finish()
+        response.finish()
     }
 }
 
 pub async fn greet(_req: HttpRequest, server_data: Data<Box<ServerData>>) -> impl Responder {
     const VERSION: &str = env!("CARGO_PKG_VERSION");
+    let config = server_data.config.read().await;
     web::Json(json!({
         "name": "web3_proxy",
-        "server_info": format!("Listen: {}:{}", server_data.options.http_addr, server_data.options.http_port),
+        "server_info": format!("Listen: {}:{}", config.http_addr, config.http_port),
         "version": VERSION,
     }))
 }
 
 pub async fn config(_req: HttpRequest, server_data: Data<Box<ServerData>>) -> impl Responder {
     const VERSION: &str = env!("CARGO_PKG_VERSION");
-    web::Json(
-        json!({"config": {"version": VERSION, "request_queue_size": server_data.options.request_queue_size}}),
-    )
+    let request_queue_size = server_data.config.read().await.request_queue_size;
+    web::Json(json!({"config": {"version": VERSION, "request_queue_size": request_queue_size}}))
+}
+
+pub async fn reload_config(
+    _req: HttpRequest,
+    server_data: Data<Box<ServerData>>,
+) -> impl Responder {
+    return_on_error_json!(server_data.reload_config().await);
+    web::Json(json!({"status": "ok"}))
+}
+
+pub async fn get_upstreams(
+    _req: HttpRequest,
+    server_data: Data<Box<ServerData>>,
+) -> impl Responder {
+    let upstreams = server_data.upstreams.lock().await.info();
+    let balance_strategy = server_data.config.read().await.balance_strategy;
+    web::Json(json!({
+        "balanceStrategy": balance_strategy,
+        "upstreams": upstreams,
+    }))
 }
 
 pub async fn set_problems(
@@ -476,9 +1196,18 @@ pub async fn set_problems(
     let key = return_on_error_json!(req.match_info().get("key").ok_or("No key provided"));
     //req.
     log::error!("set_problems: {:?}", body);
+    let problems = body.into_inner();
+    let (stored_key, stored) = (key.to_string(), problems.clone());
+    return_on_error_json!(
+        server_data
+            .run_storage(move |storage| storage.save_problems(&stored_key, &stored))
+            .await
+    );
+    let config = server_data.config.read().await;
     let mut shared_data = server_data.shared_data.lock().await;
-    let key_data = return_on_error_json!(shared_data.keys.get_mut(key).ok_or("Key not found"));
-    key_data.problems = body.into_inner();
+    // Keys can be configured before the first web3 call
+    let key_data = shared_data.key_data_mut(key, &config);
+    key_data.problems = problems;
     web::Json(json!({"status": "ok"}))
 }
 
@@ -492,13 +1221,89 @@ pub async fn get_problems(req: HttpRequest, server_data: Data<Box<ServerData>>)
     web::Json(json!({"problems": key_data.problems}))
 }
 
-pub async fn remove_endpoint_history(
+pub async fn set_routing(
     req: HttpRequest,
     server_data: Data<Box<ServerData>>,
+    body: web::Json<KeyRouting>,
 ) -> impl Responder {
     let key = return_on_error_json!(req.match_info().get("key").ok_or("No key provided"));
+    let routing = body.into_inner();
+    if !server_data.upstreams.lock().await.has_route(&routing) {
+        return web::Json(json!({"error": "No upstream matches the routing"}));
+    }
+    let (stored_key, stored) = (key.to_string(), routing.clone());
+    return_on_error_json!(
+        server_data
+            .run_storage(move |storage| storage.save_routing(&stored_key, &stored))
+            .await
+    );
+    let config = server_data.config.read().await;
     let mut shared_data = server_data.shared_data.lock().await;
-    shared_data.keys.remove(key);
+    let key_data = shared_data.key_data_mut(key, &config);
+    key_data.routing = routing;
+    web::Json(json!({"status": "ok"}))
+}
+
+pub async fn get_routing(req: HttpRequest, server_data: Data<Box<ServerData>>) -> impl Responder {
+    let key = return_on_error_json!(req.match_info().get("key").ok_or("No key provided"));
+    let shared_data = server_data.shared_data.lock().await;
+    let key_data = return_on_error_json!(shared_data.keys.get(key).ok_or("Key not found"));
+
+    web::Json(json!({"routing": key_data.routing}))
+}
+
+pub async fn set_method_access(
+    req: HttpRequest,
+    server_data: Data<Box<ServerData>>,
+    body: web::Json<MethodAccess>,
+) -> impl Responder {
+    let key = return_on_error_json!(req.match_info().get("key").ok_or("No key provided"));
+    let method_access = body.into_inner();
+    let (stored_key, stored) = (key.to_string(), method_access.clone());
+    return_on_error_json!(
+        server_data
+            .run_storage(move |storage| storage.save_method_access(&stored_key, &stored))
+            .await
+    );
+    let config = server_data.config.read().await;
+    let mut shared_data = server_data.shared_data.lock().await;
+    let key_data = shared_data.key_data_mut(key, &config);
+    key_data.method_access = method_access;
+    web::Json(json!({"status": "ok"}))
+}
+
+pub async fn get_method_access(
+    req: HttpRequest,
+    server_data: Data<Box<ServerData>>,
+) -> impl Responder {
+    let key = return_on_error_json!(req.match_info().get("key").ok_or("No key provided"));
+    let shared_data = server_data.shared_data.lock().await;
+    let key_data = return_on_error_json!(shared_data.keys.get(key).ok_or("Key not found"));
+
+    web::Json(json!({"methodAccess": key_data.method_access}))
+}
+
+pub async fn remove_endpoint_history(
+    req: HttpRequest,
+    server_data: Data<Box<ServerData>>,
+) -> impl Responder {
+    let key = return_on_error_json!(req.match_info().get("key").ok_or("No key provided"));
+    {
+        let config = server_data.config.read().await;
+        let mut shared_data = server_data.shared_data.lock().await;
+        shared_data.remove_key(key);
+        // Keys from the config file always exist, their settings are reset to the config
+        if config.keys.contains_key(key) {
+            shared_data.key_data_mut(key, &config);
+        }
+        server_data.rate_limiter.lock().await.remove_key(key);
+    }
+    let stored_key = key.to_string();
+    return_on_error_json!(
+        server_data
+            .run_storage(move |storage| storage.remove_key(&stored_key))
+            .await
+    );
 
     web::Json(json!({"status": "ok"}))
 }
@@ -507,8 +1312,16 @@ pub async fn remove_all_history(
     _req: HttpRequest,
     server_data: Data<Box<ServerData>>,
 ) -> impl Responder {
-    let mut shared_data = server_data.shared_data.lock().await;
-    shared_data.keys.clear();
+    {
+        let config = server_data.config.read().await;
+        let mut shared_data = server_data.shared_data.lock().await;
+        shared_data.remove_all_keys();
+        for key in config.keys.keys() {
+            shared_data.key_data_mut(key, &config);
+        }
+        server_data.rate_limiter.lock().await.remove_all_keys();
+    }
+    return_on_error_json!(server_data.run_storage(HistoryStorage::remove_all).await);
 
     web::Json(json!({"status": "ok"}))
 }
@@ -533,9 +1346,7 @@ pub async fn get_active_keys(
         if key_data.calls.is_empty() {
             continue;
         }
-        let last_call = key_data.calls.back().unwrap();
-        let elapsed: chrono::Duration = now - last_call.date;
-        if elapsed.num_seconds() > last_seconds {
+        if key_data.idle_time(now).num_seconds() > last_seconds {
             continue;
         }
         active_keys.push(key.clone());
@@ -592,25 +1403,150 @@ async fn main_internal() -> Result<(), Web3ProxyError> {
     }
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
     let cli: CliOptions = CliOptions::from_args();
+    let proxy_config = ProxyConfig::load(&cli)?;
+
+    let mut keys = HashMap::new();
+    let storage = match &proxy_config.history_dir {
+        Some(history_dir) => {
+            let storage = HistoryStorage::new(history_dir, proxy_config.request_queue_size)?;
+            for (key, calls) in storage.load()? {
+                let mut key_data = KeyData::new(&key, EndpointSimulateProblems::default());
+                key_data.total_calls = calls.back().map(|call| call.id + 1).unwrap_or(0);
+                key_data.total_requests = key_data.total_calls;
+                key_data.set_calls(
+                    calls
+                        .into_iter()
+                        .map(|mut call| {
+                            call.apply_limits(&proxy_config.history_limits);
+                            call
+                        })
+                        .collect(),
+                );
+                keys.insert(key, key_data);
+            }
+            for (key, problems) in storage.load_problems()? {
+                keys.entry(key.clone())
+                    .or_insert_with(|| KeyData::new(&key, EndpointSimulateProblems::default()))
+                    .problems = problems;
+            }
+            for (key, routing) in storage.load_routing()? {
+                keys.entry(key.clone())
+                    .or_insert_with(|| KeyData::new(&key, EndpointSimulateProblems::default()))
+                    .routing = routing;
+            }
+            for (key, method_access) in storage.load_method_access()? {
+                keys.entry(key.clone())
+                    .or_insert_with(|| KeyData::new(&key, EndpointSimulateProblems::default()))
+                    .method_access = method_access;
+            }
+            Some(storage)
+        }
+        None => None,
+    };
+    // Keys defined in the config file take precedence over stored settings
+    for (key, key_config) in &proxy_config.keys {
+        keys.entry(key.clone())
+            .or_insert_with(|| KeyData::new(key, EndpointSimulateProblems::default()))
+            .apply_config(key_config);
+    }
+
+    let new_server_data = {
+        let config = Arc::new(RwLock::new(proxy_config.clone()));
+        let mut shared_data = SharedData::new(keys);
+        shared_data.enforce_memory_limit(proxy_config.history_limits.memory_limit, "");
+        let shared_data = Arc::new(Mutex::new(shared_data));
+        let upstreams = Arc::new(Mutex::new(UpstreamPool::new(
+            &proxy_config.upstreams,
+            proxy_config.upstream_failure_threshold,
+            proxy_config.upstream_cooldown,
+        )));
+        let cache = Arc::new(Mutex::new(ResponseCache::new(proxy_config.cache.clone())));
+        let in_flight = Arc::new(InFlightRequests::default());
+        let rate_limiter = Arc::new(Mutex::new(RateLimiter::default()));
+        let storage = storage.map(Arc::new);
+        let upstream_client = proxy_config.upstream_client.clone();
+        move || {
+            Data::new(Box::new(ServerData {
+                cli: cli.clone(),
+                config: config.clone(),
+                shared_data: shared_data.clone(),
+                upstreams: upstreams.clone(),
+                cache: cache.clone(),
+                in_flight: in_flight.clone(),
+                rate_limiter: rate_limiter.clone(),
+                storage: storage.clone(),
+                client: build_upstream_client(&upstream_client),
+            }))
+        }
+    };
 
-    let server_data = Data::new(Box::new(ServerData {
-        options: cli.clone(),
-        shared_data: Arc::new(Mutex::new(SharedData {
-            keys: HashMap::new(),
-        })),
-    }));
+    #[cfg(unix)]
+    {
+        let server_data = new_server_data();
+        actix_web::rt::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(err) => {
+                    log::error!("Cannot listen for SIGHUP: {err}");
+                    return;
+                }
+            };
+            while hangup.recv().await.is_some() {
+                log::info!("SIGHUP received, reloading config");
+                if let Err(err) = server_data.reload_config().await {
+                    log::error!("Error reloading config: {err}");
+                }
+            }
+        });
+    }
+
+    {
+        let server_data = new_server_data();
+        actix_web::rt::spawn(async move {
+            loop {
+                let interval = server_data.config.read().await.key_cleanup_interval;
+                tokio::time::sleep(interval).await;
+                let key_idle_ttl = server_data.config.read().await.key_idle_ttl;
+                if let Some(key_idle_ttl) = key_idle_ttl {
+                    server_data.remove_idle_keys(key_idle_ttl).await;
+                }
+            }
+        });
+    }
 
+    if !proxy_config.admin.auth_enabled() {
+        log::warn!("Admin API is not protected, set admin tokens or users in config file to enable authentication");
+    }
+    let cors_allowed_origins = proxy_config.admin.cors_allowed_origins.clone();
     let server = HttpServer::new(move || {
-        let cors = actix_cors::Cors::default()
+        let server_data = new_server_data();
+        // web3 endpoint is used by dapps from any origin, allow-list applies only to admin API
+        let web3_cors = actix_cors::Cors::default()
             .allow_any_origin()
             .allow_any_method()
             .allow_any_header()
             .max_age(3600);
+        let admin_cors = if cors_allowed_origins.is_empty() {
+            actix_cors::Cors::default().allow_any_origin()
+        } else {
+            cors_allowed_origins
+                .iter()
+                .fold(actix_cors::Cors::default(), |cors, origin| {
+                    cors.allowed_origin(origin)
+                })
+        }
+        .allow_any_method()
+        .allow_any_header()
+        .max_age(3600);
 
         let scope = Scope::new("api")
             .app_data(server_data.clone())
+            .wrap(AdminAuth)
+            .wrap(admin_cors)
             .route("/", web::get().to(greet))
             .route("/config", web::get().to(config))
+            .route("/config/reload", web::post().to(reload_config))
             .route("/call/{key}/{call_no}", web::get().to(get_call))
             .route("/calls/{key}", web::get().to(get_calls))
             .route("/calls/{key}/{limit}", web::get().to(get_calls))
@@ -619,6 +1555,14 @@ async fn main_internal() -> Result<(), Web3ProxyError> {
             .route("/version", web::get().to(greet))
             .route("/problems/set/{key}", web::post().to(set_problems))
             .route("/problems/{key}", web::get().to(get_problems))
+            .route("/upstreams", web::get().to(get_upstreams))
+            .route("/routing/set/{key}", web::post().to(set_routing))
+            .route("/routing/{key}", web::get().to(get_routing))
+            .route(
+                "/method_access/set/{key}",
+                web::post().to(set_method_access),
+            )
+            .route("/method_access/{key}", web::get().to(get_method_access))
             .route("/keys", web::get().to(get_keys))
             .route("/keys/active/{seconds}", web::get().to(get_active_keys))
             .route("/keys/active", web::get().to(get_active_keys))
@@ -629,25 +1573,28 @@ async fn main_internal() -> Result<(), Web3ProxyError> {
             );
 
         App::new()
-            .wrap(cors)
             .app_data(server_data.clone())
-            .route("web3/{key}", web::get().to(web3))
-            .route("web3/{key}", web::post().to(web3))
+            .service(
+                web::resource("web3/{key}")
+                    .wrap(web3_cors)
+                    .route(web::get().to(web3))
+                    .route(web::post().to(web3)),
+            )
             .route("/api", web::get().to(greet))
             .route("/", web::get().to(redirect_to_frontend))
             .route("/frontend", web::get().to(redirect_to_frontend))
             .route("/frontend/{_:.*}", web::get().to(frontend_serve))
             .service(scope)
     })
-    .workers(cli.http_threads as usize)
-    .bind((cli.http_addr.as_str(), cli.http_port))
+    .workers(proxy_config.http_threads as usize)
+    .bind((proxy_config.http_addr.as_str(), proxy_config.http_port))
     .expect("Cannot run server")
     .run();
 
     log::info!(
         "http server starting on {}:{}",
-        cli.http_addr,
-        cli.http_port
+        proxy_config.http_addr,
+        proxy_config.http_port
     );
 
     server.await.unwrap();
@@ -666,3 +1613,157 @@ async fn main() -> Result<(), Web3ProxyError> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(body_size: usize) -> CallInfo {
+        CallInfo {
+            id: 0,
+            request: Some(StoredBody::Plain("x".repeat(body_size))),
+            response: None,
+            original_request_size: None,
+            original_response_size: None,
+            parsed_request: vec![],
+            date: chrono::Utc::now(),
+            response_time: 0.0,
+            status_code: 200,
+            upstream: None,
+            attempts: vec![],
+            cache: None,
+            coalesced: false,
+        }
+    }
+
+    /// Keys created in order, so the first key is the least recently active one
+    fn shared_data(keys: &[&str]) -> SharedData {
+        let start = chrono::Utc::now();
+        SharedData::new(
+            keys.iter()
+                .enumerate()
+                .map(|(idx, key)| {
+                    let mut key_data = KeyData::new(key, EndpointSimulateProblems::default());
+                    key_data.last_activity = start + chrono::Duration::seconds(idx as i64);
+                    (key.to_string(), key_data)
+                })
+                .collect(),
+        )
+    }
+
+    fn keys_memory(shared_data: &SharedData) -> usize {
+        shared_data
+            .keys
+            .values()
+            .map(|key_data| key_data.memory_used)
+            .sum()
+    }
+
+    #[test]
+    fn memory_used_follows_push_trim_and_remove() {
+        let mut shared_data = shared_data(&["a", "b"]);
+        for size in [100, 200, 300] {
+            shared_data.push_call("a", call(size), 10).unwrap();
+            shared_data.push_call("b", call(size * 2), 10).unwrap();
+        }
+        assert!(shared_data.push_call("unknown", call(100), 10).is_none());
+        assert_eq!(shared_data.memory_used(), keys_memory(&shared_data));
+
+        shared_data.trim_calls(1);
+        assert_eq!(shared_data.keys["a"].calls.len(), 1);
+        assert_eq!(shared_data.memory_used(), keys_memory(&shared_data));
+
+        shared_data.remove_key("a").unwrap();
+        assert_eq!(shared_data.memory_used(), keys_memory(&shared_data));
+        assert_eq!(shared_data.memory_used(), shared_data.keys["b"].memory_used);
+    }
+
+    #[test]
+    fn memory_limit_clears_least_recently_active_key_first() {
+        let mut shared_data = shared_data(&["old", "recent", "active"]);
+        for key in ["old", "recent", "active"] {
+            shared_data.push_call(key, call(1000), 10).unwrap();
+        }
+        let limit = shared_data.memory_used() - 1;
+        shared_data.enforce_memory_limit(limit, "active");
+        assert!(shared_data.keys["old"].calls.is_empty());
+        assert_eq!(shared_data.keys["recent"].calls.len(), 1);
+        assert_eq!(shared_data.keys["active"].calls.len(), 1);
+        assert_eq!(shared_data.memory_used(), keys_memory(&shared_data));
+        assert!(shared_data.memory_used() <= limit);
+    }
+
+    #[test]
+    fn memory_limit_keeps_newest_call_of_active_key() {
+        let mut shared_data = shared_data(&["other", "active"]);
+        shared_data.push_call("other", call(1000), 10).unwrap();
+        for _ in 0..3 {
+            shared_data.push_call("active", call(1000), 10).unwrap();
+        }
+        shared_data.enforce_memory_limit(0, "active");
+        assert!(shared_data.keys["other"].calls.is_empty());
+        let active = &shared_data.keys["active"];
+        assert_eq!(active.calls.len(), 1);
+        assert_eq!(active.calls[0].id, 2);
+        assert_eq!(shared_data.memory_used(), active.memory_used);
+    }
+
+    #[test]
+    fn parse_single_call() {
+        let body = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []});
+        let requests = parse_request(&body).unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].id, json!(1));
+        assert_eq!(requests[0].method, "eth_blockNumber");
+        assert!(requests[0].parse_error.is_none());
+    }
+
+    #[test]
+    fn parse_batch() {
+        let body = json!([
+            {"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance",
+                "params": ["0x0000000000000000000000000000000000000001", "latest"]},
+            {"jsonrpc": "2.0", "id": "b", "method": "eth_chainId", "params": []},
+        ]);
+        let requests = parse_request(&body).unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].id, json!(1));
+        assert_eq!(
+            requests[0]
+                .parsed_call
+                .as_ref()
+                .and_then(|call| call.address.as_deref()),
+            Some("0x0000000000000000000000000000000000000001")
+        );
+        assert_eq!(requests[1].id, json!("b"));
+        assert_eq!(requests[1].method, "eth_chainId");
+    }
+
+    #[test]
+    fn parse_empty_batch() {
+        assert!(parse_request(&json!([])).is_err());
+    }
+
+    #[test]
+    fn malformed_batch_element_keeps_id_and_error() {
+        let body = json!([
+            {"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []},
+            {"jsonrpc": "1.0", "id": 2, "method": "eth_chainId", "params": []},
+            {"jsonrpc": "2.0", "id": 3, "params": []},
+        ]);
+        let requests = parse_request(&body).unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[0].parse_error.is_none());
+        assert_eq!(requests[1].id, json!(2));
+        assert_eq!(requests[1].method, "eth_chainId");
+        assert!(requests[1]
+            .parse_error
+            .as_deref()
+            .is_some_and(|err| err.contains("jsonrpc field is not 2.0")));
+        assert_eq!(requests[2].id, json!(3));
+        assert!(requests[2]
+            .parse_error
+            .as_deref()
+            .is_some_and(|err| err.contains("method field is missing")));
+    }
+}
@@ -0,0 +1,56 @@
+/// Checks if JSON-RPC method name matches the pattern.
+/// Pattern is either exact method name or glob where `*` matches any sequence of characters,
+/// e.g. `eth_*`, `*_getLogs` or `*`.
+pub fn method_matches(pattern: &str, method: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = method.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // no wildcard in the pattern
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_name() {
+        assert!(method_matches("eth_call", "eth_call"));
+        assert!(!method_matches("eth_call", "eth_callMany"));
+        assert!(!method_matches("eth_call", "eth_cal"));
+    }
+
+    #[test]
+    fn prefix_and_suffix_globs() {
+        assert!(method_matches("eth_*", "eth_getLogs"));
+        assert!(method_matches("eth_*", "eth_"));
+        assert!(!method_matches("eth_*", "debug_traceCall"));
+        assert!(method_matches("*_getLogs", "eth_getLogs"));
+        assert!(!method_matches("*_getLogs", "eth_getLogsMany"));
+        assert!(method_matches("*", "anything"));
+    }
+
+    #[test]
+    fn wildcard_in_the_middle() {
+        assert!(method_matches("eth_get*ByHash", "eth_getBlockByHash"));
+        assert!(method_matches(
+            "eth_get*By*",
+            "eth_getTransactionByBlockHashAndIndex"
+        ));
+        assert!(!method_matches("eth_get*ByHash", "eth_getBlockByNumber"));
+        // prefix and suffix cannot overlap
+        assert!(!method_matches("ab*ba", "aba"));
+    }
+}
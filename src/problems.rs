@@ -1,27 +1,466 @@
+use crate::method_pattern::method_matches;
+use crate::rate_limit::RateLimitResponse;
+use crate::ParsedRequest;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rand_distr::{Distribution, Normal, Pareto};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::{Duration, Instant};
 
+/// Delay added before the request is handled, e.g. `{"uniform": {"minMs": 100, "maxMs": 500}}`
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+pub enum LatencyDistribution {
+    #[serde(rename_all = "camelCase")]
+    Fixed { delay_ms: f64 },
+    #[serde(rename_all = "camelCase")]
+    Uniform { min_ms: f64, max_ms: f64 },
+    /// Values below zero are clamped to zero
+    #[serde(rename_all = "camelCase")]
+    Normal { mean_ms: f64, std_dev_ms: f64 },
+    /// Long tail distribution, most of the delays are close to scale_ms,
+    /// smaller shape gives longer tail. Capped at max_ms if provided.
+    #[serde(rename_all = "camelCase")]
+    Pareto {
+        scale_ms: f64,
+        shape: f64,
+        #[serde(default)]
+        max_ms: Option<f64>,
+    },
+}
+
+const DEFAULT_HANG_MAX_MS: u64 = 180_000;
+
+/// What happens when timeout_chance is hit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TimeoutMode {
+    /// Wait and reply with 504 Gateway Timeout
+    #[serde(rename_all = "camelCase")]
+    GatewayTimeout { delay_ms: u64 },
+    /// Do not respond for hang_max_ms, then close the connection
+    Hang,
+    /// Close the connection without sending any response
+    DropConnection,
+    /// Send response headers, then close the connection after hang_max_ms without sending the body
+    StallBody,
+    /// Forward the call to upstream, but deliver the real response late
+    #[serde(rename_all = "camelCase")]
+    LateResponse { delay_ms: u64 },
+}
+
+impl Default for TimeoutMode {
+    fn default() -> Self {
+        TimeoutMode::GatewayTimeout { delay_ms: 15000 }
+    }
+}
+
+/// Deterministic throttling, like provider allowing `max_requests` per fixed window
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulatedThrottling {
+    pub max_requests: u64,
+    pub window_ms: u64,
+    /// 429 with Retry-After header or JSON-RPC error -32005
+    #[serde(default)]
+    pub response: RateLimitResponse,
+}
+
+/// Requests counted in the current throttling window of the key
+#[derive(Debug, Clone, Default)]
+pub struct ThrottleWindow {
+    start: Option<Instant>,
+    count: u64,
+}
+
+impl ThrottleWindow {
+    /// Counts the request, returns time until the window ends if the request is over the limit
+    pub fn hit(&mut self, throttling: &SimulatedThrottling, now: Instant) -> Option<Duration> {
+        let window = Duration::from_millis(throttling.window_ms.max(1));
+        let start = match self.start {
+            Some(start) if now.saturating_duration_since(start) < window => start,
+            _ => {
+                self.count = 0;
+                *self.start.insert(now)
+            }
+        };
+        self.count += 1;
+        if self.count > throttling.max_requests {
+            Some(window.saturating_sub(now.saturating_duration_since(start)))
+        } else {
+            None
+        }
+    }
+}
+
+impl LatencyDistribution {
+    pub fn sample(&self, rng: &mut impl Rng) -> Duration {
+        let delay_ms = match *self {
+            LatencyDistribution::Fixed { delay_ms } => delay_ms,
+            LatencyDistribution::Uniform { min_ms, max_ms } => {
+                if max_ms > min_ms {
+                    rng.gen_range(min_ms..max_ms)
+                } else {
+                    min_ms
+                }
+            }
+            LatencyDistribution::Normal {
+                mean_ms,
+                std_dev_ms,
+            } => match Normal::new(mean_ms, std_dev_ms) {
+                Ok(normal) => normal.sample(rng),
+                Err(err) => {
+                    log::warn!("Invalid normal latency distribution: {err}");
+                    mean_ms
+                }
+            },
+            LatencyDistribution::Pareto {
+                scale_ms,
+                shape,
+                max_ms,
+            } => {
+                let delay_ms = match Pareto::new(scale_ms, shape) {
+                    Ok(pareto) => pareto.sample(rng),
+                    Err(err) => {
+                        log::warn!("Invalid pareto latency distribution: {err}");
+                        scale_ms
+                    }
+                };
+                max_ms
+                    .map(|max_ms| delay_ms.min(max_ms))
+                    .unwrap_or(delay_ms)
+            }
+        };
+        Duration::try_from_secs_f64(delay_ms.max(0.0) / 1000.0).unwrap_or(Duration::MAX)
+    }
+}
+
+/// JSON-RPC error returned by the proxy instead of the upstream response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulatedRpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+
+impl SimulatedRpcError {
+    pub fn new(code: i64, message: &str) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+
+    pub fn to_response(&self, id: &serde_json::Value) -> serde_json::Value {
+        let mut error = json!({"code": self.code, "message": self.message});
+        if let Some(data) = &self.data {
+            error["data"] = data.clone();
+        }
+        json!({"jsonrpc": "2.0", "id": id, "error": error})
+    }
+}
+
+/// Answers the whole request with JSON-RPC errors: an array with an error for every element
+/// of a batch, or a single error. `error_for` gets `None` when the single call was not parsed.
+pub fn rpc_error_response(
+    requests: &[ParsedRequest],
+    body_json: &serde_json::Value,
+    mut error_for: impl FnMut(Option<&ParsedRequest>) -> SimulatedRpcError,
+) -> serde_json::Value {
+    if body_json.is_array() {
+        serde_json::Value::Array(
+            requests
+                .iter()
+                .map(|req| error_for(Some(req)).to_response(&req.id))
+                .collect(),
+        )
+    } else {
+        error_for(requests.first()).to_response(&body_json["id"])
+    }
+}
+
+/// Errors commonly returned by nodes, used when no custom list is provided
+fn default_rpc_errors() -> Vec<SimulatedRpcError> {
+    vec![
+        SimulatedRpcError::new(-32000, "nonce too low"),
+        SimulatedRpcError::new(-32005, "limit exceeded"),
+        SimulatedRpcError::new(-32000, "header not found"),
+        SimulatedRpcError {
+            code: 3,
+            message: "execution reverted: simulated revert".to_string(),
+            // Error(string) encoded revert reason "simulated revert"
+            data: Some(json!(
+                "0x08c379a0\
+                0000000000000000000000000000000000000000000000000000000000000020\
+                0000000000000000000000000000000000000000000000000000000000000010\
+                73696d756c617465642072657665727400000000000000000000000000000000"
+            )),
+        },
+    ]
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
 pub struct EndpointSimulateProblems {
     pub timeout_chance: f64,
+    pub timeout_mode: TimeoutMode,
+    /// Upper bound of hang and stallBody timeouts. Disconnected clients are not noticed
+    /// while the response is held back, so every hung call keeps its socket until then.
+    pub hang_max_ms: u64,
     pub error_chance: f64,
     pub malformed_response_chance: f64,
     pub skip_sending_raw_transaction_chance: f64,
     pub send_transaction_but_report_failure_chance: f64,
+    /// Key wide, ignored in method profiles
     pub allow_only_parsed_calls: bool,
+    /// Rejects batch requests with 400, off by default so batches are forwarded.
+    /// Key wide, ignored in method profiles
     pub allow_only_single_calls: bool,
+
+    /// Additional latency added to every request
+    pub latency: Option<LatencyDistribution>,
+
+    /// Requests over the limit of the window are rejected, the window is counted per key.
+    /// Key wide, ignored in method profiles.
+    pub throttling: Option<SimulatedThrottling>,
+
+    /// Chance that the request is answered with HTTP 200 and JSON-RPC error from rpc_errors
+    pub rpc_error_chance: f64,
+    /// Errors to choose from when rpc_error_chance or batch_element_error_chance is hit
+    pub rpc_errors: Vec<SimulatedRpcError>,
+
+    /// Chance that a single element of a batch response is removed
+    pub batch_drop_element_chance: f64,
+    /// Chance that a single element of a batch response loses its id
+    pub batch_missing_id_chance: f64,
+    /// Chance that a single element of a batch response is replaced with JSON-RPC error
+    pub batch_element_error_chance: f64,
+    /// Chance that a single element of a batch response has neither result nor error
+    pub batch_corrupt_element_chance: f64,
+    /// Chance that elements of a batch response are returned out of order
+    pub batch_shuffle_chance: f64,
+
+    /// Profiles used instead of this one for matching methods, first match wins.
+    /// Exact method names take precedence over globs.
+    /// Method profiles nested inside method profiles are ignored.
+    pub method_problems: Vec<MethodProblems>,
+}
+
+/// Problem profile attached to JSON-RPC method name or glob (e.g. `eth_getLogs`, `eth_*`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MethodProblems {
+    pub method: String,
+    pub problems: EndpointSimulateProblems,
 }
 
 impl Default for EndpointSimulateProblems {
     fn default() -> Self {
         Self {
             timeout_chance: 0.0,
+            timeout_mode: TimeoutMode::default(),
+            hang_max_ms: DEFAULT_HANG_MAX_MS,
             error_chance: 0.0,
             malformed_response_chance: 0.0,
             skip_sending_raw_transaction_chance: 0.0,
             send_transaction_but_report_failure_chance: 0.0,
             allow_only_parsed_calls: true,
-            allow_only_single_calls: true,
+            allow_only_single_calls: false,
+            latency: None,
+            throttling: None,
+            rpc_error_chance: 0.0,
+            rpc_errors: default_rpc_errors(),
+            batch_drop_element_chance: 0.0,
+            batch_missing_id_chance: 0.0,
+            batch_element_error_chance: 0.0,
+            batch_corrupt_element_chance: 0.0,
+            batch_shuffle_chance: 0.0,
+            method_problems: Vec::new(),
+        }
+    }
+}
+
+impl EndpointSimulateProblems {
+    pub fn has_batch_problems(&self) -> bool {
+        self.batch_drop_element_chance > 0.0
+            || self.batch_missing_id_chance > 0.0
+            || self.batch_element_error_chance > 0.0
+            || self.batch_corrupt_element_chance > 0.0
+            || self.batch_shuffle_chance > 0.0
+            || self
+                .method_problems
+                .iter()
+                .any(|m| m.problems.has_batch_problems())
+    }
+
+    /// Returns problem profile for given method, or self if no method profile matches
+    pub fn for_method(&self, method: &str) -> &EndpointSimulateProblems {
+        self.method_problems
+            .iter()
+            .find(|m| m.method == method)
+            .or_else(|| {
+                self.method_problems
+                    .iter()
+                    .find(|m| method_matches(&m.method, method))
+            })
+            .map(|m| &m.problems)
+            .unwrap_or(self)
+    }
+
+    /// Picks random error from configured list, falls back to generic internal error
+    pub fn random_rpc_error(&self, rng: &mut impl Rng) -> SimulatedRpcError {
+        self.rpc_errors
+            .choose(rng)
+            .cloned()
+            .unwrap_or_else(|| SimulatedRpcError::new(-32603, "simulated internal error"))
+    }
+}
+
+fn chance_hit(rng: &mut impl Rng, chance: f64) -> bool {
+    chance > 0.0 && rng.gen_range(0.0..1.0) < chance
+}
+
+/// Applies per element problems to the batch response returned by upstream.
+/// Every element uses the profile of the method it is answering (matched by id).
+/// Returns None if the response is not a JSON array, so it can be passed through unchanged.
+pub fn apply_batch_problems(
+    key_problems: &EndpointSimulateProblems,
+    requests: &[ParsedRequest],
+    response_body: &str,
+    rng: &mut impl Rng,
+) -> Option<String> {
+    let mut elements = match serde_json::from_str::<serde_json::Value>(response_body) {
+        Ok(serde_json::Value::Array(elements)) => elements,
+        _ => return None,
+    };
+
+    let mut result = Vec::with_capacity(elements.len());
+    for mut element in elements.drain(..) {
+        let problems = requests
+            .iter()
+            .find(|req| req.id == element["id"])
+            .map(|req| key_problems.for_method(&req.method))
+            .unwrap_or(key_problems);
+        if chance_hit(rng, problems.batch_drop_element_chance) {
+            log::info!("Batch drop element hit! (id: {})", element["id"]);
+            continue;
         }
+        if chance_hit(rng, problems.batch_element_error_chance) {
+            log::info!("Batch element error hit! (id: {})", element["id"]);
+            element = problems.random_rpc_error(rng).to_response(&element["id"]);
+        } else if chance_hit(rng, problems.batch_corrupt_element_chance) {
+            log::info!("Batch corrupt element hit! (id: {})", element["id"]);
+            if let Some(obj) = element.as_object_mut() {
+                obj.remove("result");
+                obj.remove("error");
+            }
+        }
+        if chance_hit(rng, problems.batch_missing_id_chance) {
+            log::info!("Batch missing id hit! (id: {})", element["id"]);
+            if let Some(obj) = element.as_object_mut() {
+                obj.remove("id");
+            }
+        }
+        result.push(element);
+    }
+    if chance_hit(rng, key_problems.batch_shuffle_chance) {
+        log::info!("Batch shuffle hit! ({} elements)", result.len());
+        result.shuffle(rng);
+    }
+
+    Some(serde_json::Value::Array(result).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn batch_response() -> String {
+        json!([
+            {"jsonrpc": "2.0", "id": 1, "result": "0x1"},
+            {"jsonrpc": "2.0", "id": 2, "result": "0x2"},
+        ])
+        .to_string()
+    }
+
+    fn apply(problems: &EndpointSimulateProblems, body: &str) -> Option<serde_json::Value> {
+        let requests = [
+            ParsedRequest::new(json!(1), "eth_blockNumber", vec![]),
+            ParsedRequest::new(json!(2), "eth_chainId", vec![]),
+        ];
+        let mut rng = StdRng::seed_from_u64(1);
+        apply_batch_problems(problems, &requests, body, &mut rng)
+            .map(|body| serde_json::from_str(&body).unwrap())
+    }
+
+    #[test]
+    fn no_problems_keep_batch_unchanged() {
+        let body = apply(&EndpointSimulateProblems::default(), &batch_response()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::from_str::<serde_json::Value>(&batch_response()).unwrap()
+        );
+    }
+
+    #[test]
+    fn non_array_response_is_not_changed() {
+        let body = json!({"jsonrpc": "2.0", "id": 1, "result": "0x1"}).to_string();
+        assert_eq!(apply(&EndpointSimulateProblems::default(), &body), None);
+    }
+
+    #[test]
+    fn certain_chances_apply_to_every_element() {
+        let problems = EndpointSimulateProblems {
+            batch_drop_element_chance: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(apply(&problems, &batch_response()).unwrap(), json!([]));
+
+        let problems = EndpointSimulateProblems {
+            batch_corrupt_element_chance: 1.0,
+            batch_missing_id_chance: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            apply(&problems, &batch_response()).unwrap(),
+            json!([{"jsonrpc": "2.0"}, {"jsonrpc": "2.0"}])
+        );
+    }
+
+    #[test]
+    fn element_error_uses_configured_errors() {
+        let problems = EndpointSimulateProblems {
+            batch_element_error_chance: 1.0,
+            rpc_errors: vec![SimulatedRpcError::new(-32000, "header not found")],
+            ..Default::default()
+        };
+        let body = apply(&problems, &batch_response()).unwrap();
+        assert_eq!(body[0]["id"], json!(1));
+        assert_eq!(body[0]["error"]["message"], json!("header not found"));
+        assert_eq!(body[1]["error"]["code"], json!(-32000));
+    }
+
+    #[test]
+    fn elements_use_profile_of_their_method() {
+        let problems = EndpointSimulateProblems {
+            method_problems: vec![MethodProblems {
+                method: "eth_chain*".to_string(),
+                problems: EndpointSimulateProblems {
+                    batch_drop_element_chance: 1.0,
+                    ..Default::default()
+                },
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            apply(&problems, &batch_response()).unwrap(),
+            json!([{"jsonrpc": "2.0", "id": 1, "result": "0x1"}])
+        );
     }
 }
@@ -0,0 +1,346 @@
+use crate::method_pattern::method_matches;
+use crate::problems::{rpc_error_response, SimulatedRpcError};
+use crate::ParsedRequest;
+use actix_web::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Error code used by Infura and Alchemy for requests over the limit
+pub const RATE_LIMIT_ERROR_CODE: i64 = -32005;
+
+/// How requests over the limit are rejected
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RateLimitResponse {
+    /// HTTP 429 with Retry-After header
+    #[default]
+    HttpStatus,
+    /// HTTP 200 with JSON-RPC error -32005
+    RpcError,
+}
+
+/// Token bucket refilled with `requests_per_second`, holding at most `burst` tokens
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub requests_per_second: f64,
+    /// Requests that can be sent at once after a quiet period, same as requests_per_second if not set
+    #[serde(default)]
+    pub burst: Option<f64>,
+}
+
+impl RateLimit {
+    fn capacity(&self) -> f64 {
+        self.burst.unwrap_or(self.requests_per_second).max(1.0)
+    }
+}
+
+/// Limit of calls of matching methods, counted separately for every key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MethodRateLimit {
+    /// Method name or glob, e.g. `eth_getLogs` or `debug_*`
+    pub method: String,
+    pub requests_per_second: f64,
+    #[serde(default)]
+    pub burst: Option<f64>,
+}
+
+impl MethodRateLimit {
+    fn limit(&self) -> RateLimit {
+        RateLimit {
+            requests_per_second: self.requests_per_second,
+            burst: self.burst,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub response: RateLimitResponse,
+    /// Shared by all keys
+    pub global: Option<RateLimit>,
+    /// Applied to every key, can be overridden in key config
+    pub per_key: Option<RateLimit>,
+    pub methods: Vec<MethodRateLimit>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: limit.capacity(),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.requests_per_second).min(limit.capacity());
+        self.updated = now;
+    }
+
+    /// Time after which `cost` tokens are available, cost has to be within the capacity
+    fn wait_time(&self, limit: &RateLimit, cost: f64) -> Option<Duration> {
+        if self.tokens >= cost {
+            return None;
+        }
+        if limit.requests_per_second <= 0.0 {
+            return Some(Duration::MAX);
+        }
+        Duration::try_from_secs_f64((cost - self.tokens) / limit.requests_per_second)
+            .ok()
+            .or(Some(Duration::MAX))
+    }
+}
+
+/// Request rejected because of exceeded limit
+#[derive(Debug, Clone)]
+pub struct RateLimited {
+    /// Which limit was exceeded, for logs
+    pub scope: String,
+    pub retry_after: Duration,
+}
+
+impl RateLimited {
+    /// Seconds to be sent in Retry-After header, rounded up
+    pub fn retry_after_secs(&self) -> u64 {
+        let secs = self.retry_after.as_secs_f64().ceil();
+        if secs.is_finite() && secs < u32::MAX as f64 {
+            (secs as u64).max(1)
+        } else {
+            u32::MAX as u64
+        }
+    }
+}
+
+/// Builds response for throttled request, shared by real and simulated rate limits
+pub fn throttled_response(
+    response: RateLimitResponse,
+    requests: &[ParsedRequest],
+    body_json: &serde_json::Value,
+) -> (StatusCode, String) {
+    let body = rpc_error_response(requests, body_json, |_| {
+        SimulatedRpcError::new(RATE_LIMIT_ERROR_CODE, "rate limit exceeded")
+    });
+    let status = match response {
+        RateLimitResponse::HttpStatus => StatusCode::TOO_MANY_REQUESTS,
+        RateLimitResponse::RpcError => StatusCode::OK,
+    };
+    (status, body.to_string())
+}
+
+enum BucketId {
+    Global,
+    Key,
+    Method(String),
+}
+
+impl BucketId {
+    fn describe(&self, key: &str) -> String {
+        match self {
+            BucketId::Global => "global".to_string(),
+            BucketId::Key => format!("key {key}"),
+            BucketId::Method(pattern) => format!("method {pattern} of key {key}"),
+        }
+    }
+}
+
+/// Token buckets of all limits, tokens are taken only if every limit allows the request
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    global: Option<TokenBucket>,
+    keys: HashMap<String, TokenBucket>,
+    /// Buckets by key and method pattern
+    methods: HashMap<(String, String), TokenBucket>,
+}
+
+impl RateLimiter {
+    fn bucket(&mut self, id: &BucketId, key: &str, limit: &RateLimit) -> &mut TokenBucket {
+        let now = Instant::now();
+        match id {
+            BucketId::Global => self
+                .global
+                .get_or_insert_with(|| TokenBucket::new(limit, now)),
+            BucketId::Key => self
+                .keys
+                .entry(key.to_string())
+                .or_insert_with(|| TokenBucket::new(limit, now)),
+            BucketId::Method(pattern) => self
+                .methods
+                .entry((key.to_string(), pattern.clone()))
+                .or_insert_with(|| TokenBucket::new(limit, now)),
+        }
+    }
+
+    /// Every call of a batch counts as one request. Batch larger than the burst takes
+    /// the whole bucket, otherwise it could never pass.
+    pub fn check(
+        &mut self,
+        config: &RateLimitConfig,
+        key: &str,
+        key_limit: Option<&RateLimit>,
+        methods: &[&str],
+    ) -> Result<(), RateLimited> {
+        let cost = methods.len().max(1) as f64;
+        let mut limits: Vec<(BucketId, RateLimit, f64)> = Vec::new();
+        if let Some(limit) = &config.global {
+            limits.push((BucketId::Global, limit.clone(), cost));
+        }
+        if let Some(limit) = key_limit.or(config.per_key.as_ref()) {
+            limits.push((BucketId::Key, limit.clone(), cost));
+        }
+        for method_limit in &config.methods {
+            let matching = methods
+                .iter()
+                .filter(|method| method_matches(&method_limit.method, method))
+                .count();
+            if matching > 0 {
+                limits.push((
+                    BucketId::Method(method_limit.method.clone()),
+                    method_limit.limit(),
+                    matching as f64,
+                ));
+            }
+        }
+
+        for (_, limit, cost) in &mut limits {
+            *cost = cost.min(limit.capacity());
+        }
+
+        let now = Instant::now();
+        let mut exceeded: Option<RateLimited> = None;
+        for (id, limit, cost) in &limits {
+            let bucket = self.bucket(id, key, limit);
+            bucket.refill(limit, now);
+            if let Some(wait) = bucket.wait_time(limit, *cost) {
+                if exceeded
+                    .as_ref()
+                    .map(|exceeded| wait > exceeded.retry_after)
+                    .unwrap_or(true)
+                {
+                    exceeded = Some(RateLimited {
+                        scope: id.describe(key),
+                        retry_after: wait,
+                    });
+                }
+            }
+        }
+        if let Some(exceeded) = exceeded {
+            return Err(exceeded);
+        }
+        for (id, limit, cost) in &limits {
+            self.bucket(id, key, limit).tokens -= cost;
+        }
+        Ok(())
+    }
+
+    pub fn remove_key(&mut self, key: &str) {
+        self.keys.remove(key);
+        self.methods.retain(|(bucket_key, _), _| bucket_key != key);
+    }
+
+    pub fn remove_all_keys(&mut self) {
+        self.keys.clear();
+        self.methods.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(requests_per_second: f64, burst: f64) -> RateLimit {
+        RateLimit {
+            requests_per_second,
+            burst: Some(burst),
+        }
+    }
+
+    #[test]
+    fn bucket_refills_up_to_capacity() {
+        let limit = limit(10.0, 5.0);
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(&limit, now);
+        bucket.tokens = 0.0;
+        bucket.refill(&limit, now + Duration::from_millis(200));
+        assert!((bucket.tokens - 2.0).abs() < 1e-9);
+        bucket.refill(&limit, now + Duration::from_secs(10));
+        assert_eq!(bucket.tokens, 5.0);
+    }
+
+    #[test]
+    fn wait_time_until_tokens_are_available() {
+        let limit = limit(2.0, 4.0);
+        let mut bucket = TokenBucket::new(&limit, Instant::now());
+        assert_eq!(bucket.wait_time(&limit, 4.0), None);
+        bucket.tokens = 1.0;
+        assert_eq!(
+            bucket.wait_time(&limit, 2.0),
+            Some(Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn key_limit_rejects_over_burst() {
+        let config = RateLimitConfig {
+            per_key: Some(limit(1.0, 2.0)),
+            ..Default::default()
+        };
+        let mut limiter = RateLimiter::default();
+        assert!(limiter.check(&config, "a", None, &["eth_call"]).is_ok());
+        assert!(limiter.check(&config, "a", None, &["eth_call"]).is_ok());
+        let limited = limiter
+            .check(&config, "a", None, &["eth_call"])
+            .unwrap_err();
+        assert_eq!(limited.scope, "key a");
+        assert_eq!(limited.retry_after_secs(), 1);
+        // other keys have their own buckets
+        assert!(limiter.check(&config, "b", None, &["eth_call"]).is_ok());
+    }
+
+    #[test]
+    fn batch_larger_than_burst_takes_whole_bucket() {
+        let config = RateLimitConfig {
+            per_key: Some(limit(1.0, 2.0)),
+            ..Default::default()
+        };
+        let mut limiter = RateLimiter::default();
+        let batch = ["eth_call"; 5];
+        assert!(limiter.check(&config, "a", None, &batch).is_ok());
+        let limited = limiter.check(&config, "a", None, &batch).unwrap_err();
+        assert_eq!(limited.retry_after_secs(), 2);
+    }
+
+    #[test]
+    fn tokens_are_not_taken_when_any_limit_is_exceeded() {
+        let config = RateLimitConfig {
+            per_key: Some(limit(1.0, 3.0)),
+            methods: vec![MethodRateLimit {
+                method: "debug_*".to_string(),
+                requests_per_second: 1.0,
+                burst: Some(1.0),
+            }],
+            ..Default::default()
+        };
+        let mut limiter = RateLimiter::default();
+        assert!(limiter
+            .check(&config, "a", None, &["debug_traceCall"])
+            .is_ok());
+        let limited = limiter
+            .check(&config, "a", None, &["debug_traceCall"])
+            .unwrap_err();
+        assert_eq!(limited.scope, "method debug_* of key a");
+        // the rejected call did not use the key bucket
+        assert!(limiter.check(&config, "a", None, &["eth_call"]).is_ok());
+        assert!(limiter.check(&config, "a", None, &["eth_call"]).is_ok());
+        assert!(limiter.check(&config, "a", None, &["eth_call"]).is_err());
+    }
+}
@@ -0,0 +1,64 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::web::Bytes;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::Sleep;
+
+/// Response body that produces no data until the deadline, so the client receives headers
+/// and then waits. After the deadline the body fails and actix closes the connection.
+pub struct StalledBody {
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl StalledBody {
+    pub fn new(max_duration: Duration) -> StalledBody {
+        StalledBody {
+            deadline: Box::pin(tokio::time::sleep(max_duration)),
+        }
+    }
+}
+
+impl MessageBody for StalledBody {
+    type Error = std::io::Error;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        match self.deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Some(Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "simulated stalled body expired",
+            )))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Response body that fails immediately, which makes actix close the connection
+/// before anything is sent to the client
+pub struct BrokenBody;
+
+impl MessageBody for BrokenBody {
+    type Error = std::io::Error;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Poll::Ready(Some(Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionAborted,
+            "simulated connection drop",
+        ))))
+    }
+}
@@ -0,0 +1,351 @@
+use crate::error::*;
+use crate::key_access::MethodAccess;
+use crate::problems::EndpointSimulateProblems;
+use crate::upstream::KeyRouting;
+use crate::{err_custom_create, err_from, CallInfo};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+/// Call history stored on disk as append-only JSONL file per key,
+/// problems, routing and method access settings are stored as JSON files per key.
+/// File names are hex encoded keys, so any key is safe to use as a file name.
+/// History files are written by a separate thread, so disk access does not block request handlers,
+/// and are compacted once they grow over twice the history queue size.
+pub struct HistoryStorage {
+    dir: PathBuf,
+    max_calls: usize,
+    writer: Option<mpsc::Sender<HistoryWrite>>,
+    writer_thread: Option<thread::JoinHandle<()>>,
+}
+
+const HISTORY_EXTENSION: &str = "jsonl";
+const PROBLEMS_SUFFIX: &str = ".problems.json";
+const ROUTING_SUFFIX: &str = ".routing.json";
+const METHOD_ACCESS_SUFFIX: &str = ".method_access.json";
+const SETTINGS_SUFFIXES: [&str; 3] = [PROBLEMS_SUFFIX, ROUTING_SUFFIX, METHOD_ACCESS_SUFFIX];
+
+/// Operations on history files, executed in order by the writer thread
+enum HistoryWrite {
+    Append { key: String, line: String },
+    Remove { key: String },
+    RemoveAll,
+}
+
+/// Open history file of the key and number of lines in it
+struct HistoryFile {
+    file: fs::File,
+    lines: usize,
+}
+
+/// Runs on the writer thread, keeps history files open between writes
+struct HistoryWriter {
+    dir: PathBuf,
+    max_calls: usize,
+    files: HashMap<String, HistoryFile>,
+}
+
+fn history_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{}.{}", hex::encode(key), HISTORY_EXTENSION))
+}
+
+impl HistoryWriter {
+    fn run(mut self, receiver: mpsc::Receiver<HistoryWrite>) {
+        for write in receiver {
+            let result = match write {
+                HistoryWrite::Append { key, line } => self.append(&key, &line),
+                HistoryWrite::Remove { key } => self.remove(&key),
+                HistoryWrite::RemoveAll => self.remove_all(),
+            };
+            if let Err(err) = result {
+                log::error!("Error writing call history: {err}");
+            }
+        }
+    }
+
+    fn open(&mut self, key: &str) -> Result<&mut HistoryFile, Web3ProxyError> {
+        if !self.files.contains_key(key) {
+            let path = history_path(&self.dir, key);
+            let lines = match fs::File::open(&path) {
+                Ok(file) => BufReader::new(file).lines().count(),
+                Err(_) => 0,
+            };
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(err_from!())?;
+            self.files
+                .insert(key.to_string(), HistoryFile { file, lines });
+        }
+        Ok(self.files.get_mut(key).unwrap())
+    }
+
+    fn append(&mut self, key: &str, line: &str) -> Result<(), Web3ProxyError> {
+        let max_lines = self.max_calls.max(1) * 2;
+        let history_file = self.open(key)?;
+        writeln!(history_file.file, "{line}").map_err(err_from!())?;
+        history_file.lines += 1;
+        if history_file.lines >= max_lines {
+            self.compact(key)?;
+        }
+        Ok(())
+    }
+
+    /// Keeps only max_calls latest lines of the history file
+    fn compact(&mut self, key: &str) -> Result<(), Web3ProxyError> {
+        self.files.remove(key);
+        let path = history_path(&self.dir, key);
+        let tmp_path = path.with_extension("tmp");
+        let file = fs::File::open(&path).map_err(err_from!())?;
+        let mut lines = VecDeque::new();
+        for line in BufReader::new(file).lines() {
+            lines.push_back(line.map_err(err_from!())?);
+            if lines.len() > self.max_calls {
+                lines.pop_front();
+            }
+        }
+        let mut tmp_file = BufWriter::new(fs::File::create(&tmp_path).map_err(err_from!())?);
+        for line in &lines {
+            writeln!(tmp_file, "{line}").map_err(err_from!())?;
+        }
+        tmp_file.flush().map_err(err_from!())?;
+        drop(tmp_file);
+        fs::rename(&tmp_path, &path).map_err(err_from!())?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), Web3ProxyError> {
+        self.files.remove(key);
+        let path = history_path(&self.dir, key);
+        if path.exists() {
+            fs::remove_file(path).map_err(err_from!())?;
+        }
+        Ok(())
+    }
+
+    fn remove_all(&mut self) -> Result<(), Web3ProxyError> {
+        self.files.clear();
+        for entry in fs::read_dir(&self.dir).map_err(err_from!())? {
+            let path = entry.map_err(err_from!())?.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some(HISTORY_EXTENSION) {
+                fs::remove_file(path).map_err(err_from!())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn decode_key(encoded: &str) -> Option<String> {
+    hex::decode(encoded)
+        .ok()
+        .and_then(|key| String::from_utf8(key).ok())
+}
+
+impl HistoryStorage {
+    pub fn new(dir: &Path, max_calls: usize) -> Result<HistoryStorage, Web3ProxyError> {
+        fs::create_dir_all(dir).map_err(err_from!())?;
+        let (writer, receiver) = mpsc::channel();
+        let history_writer = HistoryWriter {
+            dir: dir.to_path_buf(),
+            max_calls,
+            files: HashMap::new(),
+        };
+        let writer_thread = thread::Builder::new()
+            .name("history-writer".to_string())
+            .spawn(move || history_writer.run(receiver))
+            .map_err(err_from!())?;
+        Ok(HistoryStorage {
+            dir: dir.to_path_buf(),
+            max_calls,
+            writer: Some(writer),
+            writer_thread: Some(writer_thread),
+        })
+    }
+
+    fn send(&self, write: HistoryWrite) -> Result<(), Web3ProxyError> {
+        self.writer
+            .as_ref()
+            .and_then(|writer| writer.send(write).ok())
+            .ok_or_else(|| err_custom_create!("History writer is not running"))
+    }
+
+    fn settings_path(&self, key: &str, suffix: &str) -> PathBuf {
+        self.dir.join(format!("{}{}", hex::encode(key), suffix))
+    }
+
+    /// Loads stored history of all keys, keeping at most max_calls latest calls per key
+    pub fn load(&self) -> Result<HashMap<String, VecDeque<CallInfo>>, Web3ProxyError> {
+        let mut history = HashMap::new();
+        for entry in fs::read_dir(&self.dir).map_err(err_from!())? {
+            let path = entry.map_err(err_from!())?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(HISTORY_EXTENSION) {
+                continue;
+            }
+            let Some(key) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(decode_key)
+            else {
+                log::warn!("Skipping unknown history file {}", path.display());
+                continue;
+            };
+
+            let file = fs::File::open(&path).map_err(err_from!())?;
+            let mut calls = VecDeque::new();
+            for line in BufReader::new(file).lines() {
+                let line = line.map_err(err_from!())?;
+                match serde_json::from_str::<CallInfo>(&line) {
+                    Ok(call) => calls.push_back(call),
+                    // last line can be incomplete if the proxy was killed while writing
+                    Err(err) => log::warn!("Skipping broken entry in {}: {err}", path.display()),
+                }
+                if calls.len() > self.max_calls {
+                    calls.pop_front();
+                }
+            }
+            log::info!("Loaded {} calls of key {key} from history", calls.len());
+            history.insert(key, calls);
+        }
+        Ok(history)
+    }
+
+    /// Queues the call to be appended to the history file of the key
+    pub fn append(&self, key: &str, call: &CallInfo) -> Result<(), Web3ProxyError> {
+        let line = serde_json::to_string(call)
+            .map_err(|e| err_custom_create!("Cannot serialize call: {e}"))?;
+        self.send(HistoryWrite::Append {
+            key: key.to_string(),
+            line,
+        })
+    }
+
+    /// Loads settings of all keys stored in files with given suffix
+    fn load_settings<T: DeserializeOwned>(
+        &self,
+        suffix: &str,
+    ) -> Result<HashMap<String, T>, Web3ProxyError> {
+        let mut settings = HashMap::new();
+        for entry in fs::read_dir(&self.dir).map_err(err_from!())? {
+            let path = entry.map_err(err_from!())?.path();
+            let Some(key) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(suffix))
+                .and_then(decode_key)
+            else {
+                continue;
+            };
+            let content = fs::read_to_string(&path).map_err(err_from!())?;
+            match serde_json::from_str::<T>(&content) {
+                Ok(key_settings) => {
+                    settings.insert(key, key_settings);
+                }
+                Err(err) => log::warn!("Skipping broken settings file {}: {err}", path.display()),
+            }
+        }
+        Ok(settings)
+    }
+
+    fn save_settings<T: Serialize>(
+        &self,
+        key: &str,
+        suffix: &str,
+        settings: &T,
+    ) -> Result<(), Web3ProxyError> {
+        let content = serde_json::to_string_pretty(settings)
+            .map_err(|e| err_custom_create!("Cannot serialize settings: {e}"))?;
+        let path = self.settings_path(key, suffix);
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, content).map_err(err_from!())?;
+        fs::rename(&tmp_path, &path).map_err(err_from!())?;
+        Ok(())
+    }
+
+    /// Loads stored problem settings of all keys
+    pub fn load_problems(
+        &self,
+    ) -> Result<HashMap<String, EndpointSimulateProblems>, Web3ProxyError> {
+        self.load_settings(PROBLEMS_SUFFIX)
+    }
+
+    pub fn save_problems(
+        &self,
+        key: &str,
+        problems: &EndpointSimulateProblems,
+    ) -> Result<(), Web3ProxyError> {
+        self.save_settings(key, PROBLEMS_SUFFIX, problems)
+    }
+
+    /// Loads stored upstream routing of all keys
+    pub fn load_routing(&self) -> Result<HashMap<String, KeyRouting>, Web3ProxyError> {
+        self.load_settings(ROUTING_SUFFIX)
+    }
+
+    pub fn save_routing(&self, key: &str, routing: &KeyRouting) -> Result<(), Web3ProxyError> {
+        self.save_settings(key, ROUTING_SUFFIX, routing)
+    }
+
+    /// Loads stored method allow and deny lists of all keys
+    pub fn load_method_access(&self) -> Result<HashMap<String, MethodAccess>, Web3ProxyError> {
+        self.load_settings(METHOD_ACCESS_SUFFIX)
+    }
+
+    pub fn save_method_access(
+        &self,
+        key: &str,
+        method_access: &MethodAccess,
+    ) -> Result<(), Web3ProxyError> {
+        self.save_settings(key, METHOD_ACCESS_SUFFIX, method_access)
+    }
+
+    pub fn remove_key(&self, key: &str) -> Result<(), Web3ProxyError> {
+        self.send(HistoryWrite::Remove {
+            key: key.to_string(),
+        })?;
+        for path in SETTINGS_SUFFIXES
+            .iter()
+            .map(|suffix| self.settings_path(key, suffix))
+        {
+            if path.exists() {
+                fs::remove_file(path).map_err(err_from!())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove_all(&self) -> Result<(), Web3ProxyError> {
+        self.send(HistoryWrite::RemoveAll)?;
+        for entry in fs::read_dir(&self.dir).map_err(err_from!())? {
+            let path = entry.map_err(err_from!())?.path();
+            let is_stored_file = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| {
+                    SETTINGS_SUFFIXES
+                        .iter()
+                        .any(|suffix| name.ends_with(suffix))
+                })
+                .unwrap_or(false);
+            if is_stored_file {
+                fs::remove_file(path).map_err(err_from!())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for HistoryStorage {
+    /// Waits until queued calls are written
+    fn drop(&mut self) {
+        self.writer.take();
+        if let Some(writer_thread) = self.writer_thread.take() {
+            let _ = writer_thread.join();
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::io::{Read, Write};
+
+/// Bodies shorter than this are not worth compressing
+const MIN_COMPRESSED_SIZE: usize = 256;
+
+/// Limits of memory used by call history
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryLimits {
+    /// Longer request and response bodies are truncated before they are stored
+    pub max_body_size: usize,
+    /// Memory used by history of all keys, least recently active keys are evicted over it
+    pub memory_limit: usize,
+    pub compress_bodies: bool,
+}
+
+/// Request or response body kept in call history.
+/// Always serialized as plain string, so compression is not visible in the API and history files.
+#[derive(Debug, Clone)]
+pub enum StoredBody {
+    Plain(String),
+    Compressed { data: Vec<u8>, len: usize },
+}
+
+impl StoredBody {
+    pub fn new(text: String, compress: bool) -> StoredBody {
+        if !compress || text.len() < MIN_COMPRESSED_SIZE {
+            return StoredBody::Plain(text);
+        }
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        match encoder
+            .write_all(text.as_bytes())
+            .and_then(|_| encoder.finish())
+        {
+            Ok(data) if data.len() < text.len() => StoredBody::Compressed {
+                data,
+                len: text.len(),
+            },
+            Ok(_) => StoredBody::Plain(text),
+            Err(err) => {
+                log::warn!("Cannot compress stored body: {err}");
+                StoredBody::Plain(text)
+            }
+        }
+    }
+
+    pub fn text(&self) -> Cow<'_, str> {
+        match self {
+            StoredBody::Plain(text) => Cow::Borrowed(text),
+            StoredBody::Compressed { data, len } => {
+                let mut text = String::with_capacity(*len);
+                if let Err(err) = DeflateDecoder::new(data.as_slice()).read_to_string(&mut text) {
+                    log::error!("Cannot decompress stored body: {err}");
+                }
+                Cow::Owned(text)
+            }
+        }
+    }
+
+    pub fn into_text(self) -> String {
+        match self {
+            StoredBody::Plain(text) => text,
+            compressed => compressed.text().into_owned(),
+        }
+    }
+
+    /// Bytes used on the heap
+    pub fn memory_size(&self) -> usize {
+        match self {
+            StoredBody::Plain(text) => text.capacity(),
+            StoredBody::Compressed { data, .. } => data.capacity(),
+        }
+    }
+}
+
+impl Serialize for StoredBody {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.text())
+    }
+}
+
+impl<'de> Deserialize<'de> for StoredBody {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(StoredBody::Plain)
+    }
+}
+
+/// Cuts the body to at most `max_size` bytes, returns original size if the body was cut
+pub fn truncate_body(mut body: String, max_size: usize) -> (String, Option<usize>) {
+    if body.len() <= max_size {
+        return (body, None);
+    }
+    let original_size = body.len();
+    let mut cut = max_size;
+    while !body.is_char_boundary(cut) {
+        cut -= 1;
+    }
+    body.truncate(cut);
+    body.shrink_to_fit();
+    (body, Some(original_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_keeps_short_body() {
+        assert_eq!(
+            truncate_body("abc".to_string(), 3),
+            ("abc".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn truncate_cuts_at_char_boundary() {
+        // "ż" takes two bytes, cutting after 2 bytes would split it
+        let (body, original_size) = truncate_body("ażb".to_string(), 2);
+        assert_eq!(body, "a");
+        assert_eq!(original_size, Some(4));
+    }
+
+    #[test]
+    fn compressed_body_round_trip() {
+        let text = "{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":\"0x\"}".repeat(50);
+        let body = StoredBody::new(text.clone(), true);
+        assert!(matches!(body, StoredBody::Compressed { .. }));
+        assert!(body.memory_size() < text.len());
+        assert_eq!(body.text(), text);
+        assert_eq!(
+            serde_json::to_string(&body).unwrap(),
+            serde_json::to_string(&text).unwrap()
+        );
+    }
+
+    #[test]
+    fn short_body_is_not_compressed() {
+        let body = StoredBody::new("short".to_string(), true);
+        assert!(matches!(body, StoredBody::Plain(_)));
+        assert_eq!(body.into_text(), "short");
+    }
+}
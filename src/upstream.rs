@@ -0,0 +1,783 @@
+use crate::method_pattern::method_matches;
+use actix_web::error::PayloadError;
+use actix_web::http::StatusCode;
+use awc::error::SendRequestError;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Upstream RPC endpoint the calls are forwarded to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// Name used in history and stats, url is used if not given
+    #[serde(default)]
+    pub name: String,
+    pub url: String,
+    /// Upstreams with lower priority value are tried first
+    #[serde(default)]
+    pub priority: i32,
+    /// Share of traffic when weighted balancing is used
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// Network served by the upstream, e.g. polygon, mumbai or holesky
+    #[serde(default)]
+    pub chain: Option<String>,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// How the upstream for a call is chosen, remaining upstreams are used for failover
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BalanceStrategy {
+    /// Always start with the upstream with the lowest priority value
+    #[default]
+    Failover,
+    RoundRobin,
+    /// Random upstream, chosen proportionally to its weight
+    Weighted,
+    /// Upstream with the lowest average response time
+    LeastLatency,
+    /// Upstream with the lowest number of requests in progress
+    LeastInFlight,
+}
+
+/// Upstreams used by the key, all upstreams are used if neither upstream nor chain is set
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct KeyRouting {
+    /// Name of the only upstream used by the key
+    pub upstream: Option<String>,
+    /// Only upstreams of this chain are used by the key
+    pub chain: Option<String>,
+    /// Overrides global balance strategy for the key
+    pub balance_strategy: Option<BalanceStrategy>,
+}
+
+impl KeyRouting {
+    /// Identifies upstreams used by the key, so results of different networks are not mixed
+    pub fn scope(&self) -> String {
+        if let Some(upstream) = &self.upstream {
+            format!("upstream:{upstream}")
+        } else if let Some(chain) = &self.chain {
+            format!("chain:{chain}")
+        } else {
+            "default".to_string()
+        }
+    }
+
+    pub fn allows(&self, upstream: &UpstreamConfig) -> bool {
+        self.upstream
+            .as_ref()
+            .map(|name| name == &upstream.name)
+            .unwrap_or(true)
+            && self
+                .chain
+                .as_ref()
+                .map(|chain| Some(chain) == upstream.chain.as_ref())
+                .unwrap_or(true)
+    }
+}
+
+/// Sends calls of matching methods to chosen upstreams instead of the ones allowed by key routing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MethodRoute {
+    /// Method name or glob, e.g. `eth_getLogs` or `debug_*`
+    pub method: String,
+    /// Names of upstreams used for matching calls
+    pub upstreams: Vec<String>,
+    /// Rule applies only to keys routed to this chain
+    #[serde(default)]
+    pub chain: Option<String>,
+}
+
+impl MethodRoute {
+    /// Rule matches if it applies to the key and all methods of the request match the pattern
+    pub fn matches(&self, routing: &KeyRouting, methods: &[&str]) -> bool {
+        if self.chain.is_some() && self.chain != routing.chain {
+            return false;
+        }
+        !methods.is_empty()
+            && methods
+                .iter()
+                .all(|method| method_matches(&self.method, method))
+    }
+}
+
+/// Smoothing factor of the average latency, higher value reacts faster to changes
+const LATENCY_SMOOTHING: f64 = 0.2;
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpstreamStats {
+    pub request_count: u64,
+    pub error_count: u64,
+    pub in_flight: u64,
+    /// Exponentially weighted average of response time in seconds
+    pub avg_latency: Option<f64>,
+    pub last_error: Option<String>,
+}
+
+impl UpstreamStats {
+    pub fn error_rate(&self) -> f64 {
+        if self.request_count == 0 {
+            0.0
+        } else {
+            self.error_count as f64 / self.request_count as f64
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpstreamInfo {
+    pub name: String,
+    pub url: String,
+    pub priority: i32,
+    pub weight: u32,
+    pub chain: Option<String>,
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+    pub error_rate: f64,
+    pub stats: UpstreamStats,
+}
+
+impl UpstreamConfig {
+    pub fn new(url: &str) -> UpstreamConfig {
+        UpstreamConfig {
+            name: url.to_string(),
+            url: url.to_string(),
+            priority: 0,
+            weight: default_weight(),
+            chain: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct UpstreamHealth {
+    consecutive_failures: u32,
+    unhealthy_until: Option<Instant>,
+}
+
+impl UpstreamHealth {
+    fn is_healthy(&self, now: Instant) -> bool {
+        self.unhealthy_until
+            .map(|until| now >= until)
+            .unwrap_or(true)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct UpstreamState {
+    config: UpstreamConfig,
+    health: UpstreamHealth,
+    stats: UpstreamStats,
+}
+
+/// Upstreams shared between workers, together with their health.
+/// Upstream is considered unhealthy after failure_threshold consecutive failures
+/// and is tried only as a last resort until the cooldown passes.
+#[derive(Debug)]
+pub struct UpstreamPool {
+    upstreams: Vec<UpstreamState>,
+    failure_threshold: u32,
+    cooldown: Duration,
+    round_robin_counter: usize,
+}
+
+impl UpstreamPool {
+    pub fn new(
+        upstreams: &[UpstreamConfig],
+        failure_threshold: u32,
+        cooldown: Duration,
+    ) -> UpstreamPool {
+        let mut pool = UpstreamPool {
+            upstreams: Vec::new(),
+            failure_threshold,
+            cooldown,
+            round_robin_counter: 0,
+        };
+        pool.update(upstreams, failure_threshold, cooldown);
+        pool
+    }
+
+    /// Replaces list of upstreams, health and stats of upstreams with the same name are kept
+    pub fn update(
+        &mut self,
+        upstreams: &[UpstreamConfig],
+        failure_threshold: u32,
+        cooldown: Duration,
+    ) {
+        self.upstreams = upstreams
+            .iter()
+            .map(|config| {
+                let previous = self
+                    .upstreams
+                    .iter()
+                    .find(|state| state.config.name == config.name);
+                UpstreamState {
+                    config: config.clone(),
+                    health: previous
+                        .map(|state| state.health.clone())
+                        .unwrap_or_default(),
+                    stats: previous
+                        .map(|state| state.stats.clone())
+                        .unwrap_or_default(),
+                }
+            })
+            .collect();
+        self.failure_threshold = failure_threshold;
+        self.cooldown = cooldown;
+    }
+
+    /// Chain the key is bound to, directly or through its upstream
+    fn key_chain(&self, routing: &KeyRouting) -> Option<String> {
+        routing.chain.clone().or_else(|| {
+            let upstream = routing.upstream.as_ref()?;
+            self.upstreams
+                .iter()
+                .find(|state| &state.config.name == upstream)
+                .and_then(|state| state.config.chain.clone())
+        })
+    }
+
+    /// Returns upstreams allowed by the routing in order they should be tried.
+    /// If method route is given, its upstreams are used instead of the ones allowed by the key,
+    /// but only those serving the chain of the key. Key routing is used if none of them does,
+    /// so responses of other networks are never returned to the key.
+    /// Healthy upstreams are ordered by the strategy and always go before unhealthy ones,
+    /// which are ordered by priority.
+    pub fn candidates(
+        &mut self,
+        routing: &KeyRouting,
+        method_route: Option<&MethodRoute>,
+        default_strategy: BalanceStrategy,
+    ) -> Vec<UpstreamConfig> {
+        let now = Instant::now();
+        let strategy = routing.balance_strategy.unwrap_or(default_strategy);
+        let key_chain = self.key_chain(routing);
+        let route_upstreams: Vec<&UpstreamState> = match method_route {
+            Some(method_route) => self
+                .upstreams
+                .iter()
+                .filter(|state| method_route.upstreams.contains(&state.config.name))
+                .filter(|state| {
+                    key_chain.is_none() || state.config.chain.as_ref() == key_chain.as_ref()
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        let upstreams = if route_upstreams.is_empty() {
+            self.upstreams
+                .iter()
+                .filter(|state| routing.allows(&state.config))
+                .collect()
+        } else {
+            route_upstreams
+        };
+        let (mut healthy, mut unhealthy): (Vec<&UpstreamState>, Vec<&UpstreamState>) = upstreams
+            .into_iter()
+            .partition(|state| state.health.is_healthy(now));
+        healthy.sort_by_key(|state| state.config.priority);
+        unhealthy.sort_by_key(|state| state.config.priority);
+
+        match strategy {
+            BalanceStrategy::Failover => {}
+            BalanceStrategy::RoundRobin => {
+                if !healthy.is_empty() {
+                    let shift = self.round_robin_counter % healthy.len();
+                    healthy.rotate_left(shift);
+                }
+                self.round_robin_counter = self.round_robin_counter.wrapping_add(1);
+            }
+            BalanceStrategy::Weighted => {
+                let mut rng = rand::thread_rng();
+                let mut remaining = std::mem::take(&mut healthy);
+                while !remaining.is_empty() {
+                    let total_weight: u64 = remaining
+                        .iter()
+                        .map(|state| state.config.weight as u64)
+                        .sum();
+                    let idx = if total_weight == 0 {
+                        0
+                    } else {
+                        let mut point = rng.gen_range(0..total_weight);
+                        remaining
+                            .iter()
+                            .position(|state| {
+                                let weight = state.config.weight as u64;
+                                if point < weight {
+                                    true
+                                } else {
+                                    point -= weight;
+                                    false
+                                }
+                            })
+                            .unwrap_or(0)
+                    };
+                    healthy.push(remaining.remove(idx));
+                }
+            }
+            BalanceStrategy::LeastLatency => {
+                // upstreams without measurements go first, so they get measured
+                healthy.sort_by(|a, b| {
+                    a.stats
+                        .avg_latency
+                        .unwrap_or(0.0)
+                        .total_cmp(&b.stats.avg_latency.unwrap_or(0.0))
+                });
+            }
+            BalanceStrategy::LeastInFlight => {
+                healthy.sort_by_key(|state| state.stats.in_flight);
+            }
+        }
+
+        healthy
+            .into_iter()
+            .chain(unhealthy)
+            .map(|state| state.config.clone())
+            .collect()
+    }
+
+    /// Checks if there is any upstream the routing can use
+    pub fn has_route(&self, routing: &KeyRouting) -> bool {
+        self.upstreams
+            .iter()
+            .any(|state| routing.allows(&state.config))
+    }
+
+    fn find_mut(&mut self, name: &str) -> Option<&mut UpstreamState> {
+        self.upstreams
+            .iter_mut()
+            .find(|state| state.config.name == name)
+    }
+
+    fn start_request(&mut self, name: &str) {
+        if let Some(state) = self.find_mut(name) {
+            state.stats.in_flight += 1;
+        }
+    }
+
+    fn cancel_request(&mut self, name: &str) {
+        if let Some(state) = self.find_mut(name) {
+            state.stats.in_flight = state.stats.in_flight.saturating_sub(1);
+        }
+    }
+
+    fn finish_request(&mut self, name: &str, error: Option<String>, latency: Duration) {
+        let failure_threshold = self.failure_threshold;
+        let cooldown = self.cooldown;
+        let Some(state) = self.find_mut(name) else {
+            return;
+        };
+        state.stats.in_flight = state.stats.in_flight.saturating_sub(1);
+        state.stats.request_count += 1;
+        let latency = latency.as_secs_f64();
+        state.stats.avg_latency = Some(match state.stats.avg_latency {
+            Some(avg) => avg + (latency - avg) * LATENCY_SMOOTHING,
+            None => latency,
+        });
+
+        let Some(error) = error else {
+            state.health = UpstreamHealth::default();
+            return;
+        };
+        state.stats.error_count += 1;
+        state.stats.last_error = Some(error);
+        state.health.consecutive_failures += 1;
+        if state.health.consecutive_failures >= failure_threshold {
+            log::warn!(
+                "Upstream {} marked unhealthy after {} failures",
+                name,
+                state.health.consecutive_failures
+            );
+            state.health.unhealthy_until = Some(Instant::now() + cooldown);
+        }
+    }
+
+    pub fn info(&self) -> Vec<UpstreamInfo> {
+        let now = Instant::now();
+        self.upstreams
+            .iter()
+            .map(|state| UpstreamInfo {
+                name: state.config.name.clone(),
+                url: state.config.url.clone(),
+                priority: state.config.priority,
+                weight: state.config.weight,
+                chain: state.config.chain.clone(),
+                healthy: state.health.is_healthy(now),
+                consecutive_failures: state.health.consecutive_failures,
+                error_rate: state.stats.error_rate(),
+                stats: state.stats.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Request in progress to the upstream, counted as in flight until finished or dropped
+pub struct UpstreamRequest {
+    pool: Arc<Mutex<UpstreamPool>>,
+    name: Option<String>,
+    start: Instant,
+}
+
+impl UpstreamRequest {
+    pub async fn start(pool: &Arc<Mutex<UpstreamPool>>, name: &str) -> UpstreamRequest {
+        pool.lock().await.start_request(name);
+        UpstreamRequest {
+            pool: pool.clone(),
+            name: Some(name.to_string()),
+            start: Instant::now(),
+        }
+    }
+
+    /// Updates stats and health of the upstream, error is None if the request succeeded
+    pub async fn finish(mut self, error: Option<String>) {
+        if let Some(name) = self.name.take() {
+            self.pool
+                .lock()
+                .await
+                .finish_request(&name, error, self.start.elapsed());
+        }
+    }
+}
+
+impl Drop for UpstreamRequest {
+    fn drop(&mut self) {
+        // handler was dropped before the request finished, e.g. the client disconnected
+        if let Some(name) = self.name.take() {
+            let pool = self.pool.clone();
+            actix_web::rt::spawn(async move {
+                pool.lock().await.cancel_request(&name);
+            });
+        }
+    }
+}
+
+/// Settings of HTTP client used for upstream calls
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamClientConfig {
+    /// Time limit for the whole request, until response headers are received
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    /// Time limit for receiving response body, counted after headers are received
+    pub read_timeout: Duration,
+    /// Longer response bodies are rejected, e.g. huge eth_getLogs results
+    pub max_response_size: usize,
+    /// How long idle connection is kept in the pool
+    pub keep_alive: Duration,
+    /// Maximum number of simultaneous connections per worker
+    pub max_connections: usize,
+}
+
+/// Creates client with connection pool, has to be called on the worker thread that uses it
+pub fn build_upstream_client(config: &UpstreamClientConfig) -> awc::Client {
+    awc::Client::builder()
+        .connector(
+            awc::Connector::new()
+                .timeout(config.connect_timeout)
+                .conn_keep_alive(config.keep_alive)
+                .limit(config.max_connections),
+        )
+        .timeout(config.timeout)
+        .finish()
+}
+
+/// Failed attempt to call upstream
+#[derive(Debug, Clone)]
+pub enum UpstreamError {
+    /// Connection could not be established, so the request was not delivered
+    Connect(String),
+    /// Request was sent, but response headers did not arrive in time
+    Timeout,
+    /// Response body did not arrive in time
+    ReadTimeout,
+    /// Upstream responded with error status
+    Status(StatusCode),
+    /// Response body is over the size limit, upstream itself works fine
+    ResponseTooLarge(usize),
+    /// Any other error, request might have been delivered
+    Other(String),
+}
+
+impl Display for UpstreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamError::Connect(err) => write!(f, "Error connecting: {err}"),
+            UpstreamError::Timeout => write!(f, "Timeout while waiting for response"),
+            UpstreamError::ReadTimeout => write!(f, "Timeout while reading response body"),
+            UpstreamError::Status(status) => write!(f, "Upstream returned {status}"),
+            UpstreamError::ResponseTooLarge(limit) => {
+                write!(f, "Response body larger than {limit} bytes")
+            }
+            UpstreamError::Other(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+/// Controls how many times a failing upstream is called again before failing over to the next one
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every next one
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Response statuses that are worth retrying, e.g. 429 or 503
+    pub retry_on_status: Vec<u16>,
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `retry` (counted from 0)
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+
+    /// Checks if the failed call can be sent again.
+    /// Transactions are resent only if the upstream certainly did not process them,
+    /// otherwise the same transaction could be broadcast by several upstreams.
+    pub fn is_retryable(&self, error: &UpstreamError, sends_transaction: bool) -> bool {
+        match error {
+            UpstreamError::Connect(_) => true,
+            UpstreamError::Status(status) if status.as_u16() == 429 => true,
+            _ if sends_transaction => false,
+            UpstreamError::Timeout | UpstreamError::ReadTimeout => true,
+            UpstreamError::Status(status) => self.retry_on_status.contains(&status.as_u16()),
+            UpstreamError::ResponseTooLarge(_) | UpstreamError::Other(_) => false,
+        }
+    }
+}
+
+impl UpstreamError {
+    /// Too large response is caused by the call, not by the upstream,
+    /// so it does not count against upstream health and is not sent to other upstreams
+    pub fn is_upstream_failure(&self) -> bool {
+        !matches!(self, UpstreamError::ResponseTooLarge(_))
+    }
+}
+
+/// Single call to upstream, recorded in call history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpstreamAttempt {
+    pub upstream: String,
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+    pub response_time: f64,
+}
+
+/// Result of forwarding call to upstreams, with all attempts made
+pub struct ForwardOutcome {
+    pub result: Result<(StatusCode, String), UpstreamError>,
+    /// Name of the upstream that produced the result
+    pub upstream: Option<String>,
+    pub attempts: Vec<UpstreamAttempt>,
+}
+
+/// Sends call to candidates in order, retrying each one according to the policy
+pub async fn forward_to_upstreams(
+    client: &awc::Client,
+    pool: &Arc<Mutex<UpstreamPool>>,
+    candidates: Vec<UpstreamConfig>,
+    body: &serde_json::Value,
+    client_config: &UpstreamClientConfig,
+    retry_policy: &RetryPolicy,
+    sends_transaction: bool,
+) -> ForwardOutcome {
+    let mut outcome = ForwardOutcome {
+        result: Err(UpstreamError::Other(
+            "No upstream available for the key".to_string(),
+        )),
+        upstream: None,
+        attempts: Vec::new(),
+    };
+    'upstreams: for upstream in candidates {
+        for retry in 0..=retry_policy.retries {
+            if retry > 0 {
+                let backoff = retry_policy.backoff(retry - 1);
+                log::info!(
+                    "Retrying upstream {} in {}ms (retry {}/{})",
+                    upstream.name,
+                    backoff.as_millis(),
+                    retry,
+                    retry_policy.retries
+                );
+                tokio::time::sleep(backoff).await;
+            }
+            let upstream_request = UpstreamRequest::start(pool, &upstream.name).await;
+            let attempt_start = Instant::now();
+            let result = send_to_upstream(client, &upstream.url, body, client_config).await;
+            let error = match &result {
+                Ok((status, _)) if status.is_server_error() || status.as_u16() == 429 => {
+                    Some(UpstreamError::Status(*status))
+                }
+                Ok(_) => None,
+                Err(err) => Some(err.clone()),
+            };
+            outcome.attempts.push(UpstreamAttempt {
+                upstream: upstream.name.clone(),
+                status_code: result.as_ref().ok().map(|(status, _)| status.as_u16()),
+                error: error.as_ref().map(|err| err.to_string()),
+                response_time: attempt_start.elapsed().as_secs_f64(),
+            });
+            upstream_request
+                .finish(
+                    error
+                        .as_ref()
+                        .filter(|err| err.is_upstream_failure())
+                        .map(|err| err.to_string()),
+                )
+                .await;
+            outcome.upstream = Some(upstream.name.clone());
+            // failed response is kept, in case no other upstream does better
+            outcome.result = result;
+
+            let Some(error) = error else {
+                break 'upstreams;
+            };
+            log::warn!("Upstream {} failed: {}", upstream.name, error);
+            if !error.is_upstream_failure() {
+                break 'upstreams;
+            }
+            if !retry_policy.is_retryable(&error, sends_transaction) {
+                if sends_transaction {
+                    log::warn!("Transaction might have been delivered, not sending it again");
+                    break 'upstreams;
+                }
+                break;
+            }
+        }
+    }
+    outcome
+}
+
+/// Posts JSON-RPC request to upstream and returns status and body of the response
+pub async fn send_to_upstream(
+    client: &awc::Client,
+    url: &str,
+    body: &serde_json::Value,
+    client_config: &UpstreamClientConfig,
+) -> Result<(StatusCode, String), UpstreamError> {
+    let response = client
+        .post(url)
+        .send_json(body)
+        .await
+        .map_err(|err| match err {
+            SendRequestError::Connect(err) => UpstreamError::Connect(err.to_string()),
+            SendRequestError::Timeout => UpstreamError::Timeout,
+            err => UpstreamError::Other(format!("Error sending request: {err}")),
+        })?;
+    log::debug!("res: {:?}", response);
+    let mut response = response.timeout(client_config.read_timeout);
+    let body = response
+        .body()
+        .limit(client_config.max_response_size)
+        .await
+        .map_err(|err| match err {
+            PayloadError::Io(err) if err.kind() == std::io::ErrorKind::TimedOut => {
+                UpstreamError::ReadTimeout
+            }
+            PayloadError::Overflow => {
+                UpstreamError::ResponseTooLarge(client_config.max_response_size)
+            }
+            err => UpstreamError::Other(format!("Error getting body: {err}")),
+        })?;
+    let body_str = String::from_utf8(body.to_vec())
+        .map_err(|err| UpstreamError::Other(format!("Error getting body: {err}")))?;
+    Ok((response.status(), body_str))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstream(name: &str, chain: &str) -> UpstreamConfig {
+        UpstreamConfig {
+            name: name.to_string(),
+            chain: Some(chain.to_string()),
+            ..UpstreamConfig::new(&format!("http://{name}"))
+        }
+    }
+
+    fn pool() -> UpstreamPool {
+        UpstreamPool::new(
+            &[
+                upstream("mumbai", "mumbai"),
+                upstream("polygon-archive", "polygon"),
+                upstream("mumbai-archive", "mumbai"),
+            ],
+            3,
+            Duration::from_secs(30),
+        )
+    }
+
+    fn route(upstreams: &[&str]) -> MethodRoute {
+        MethodRoute {
+            method: "eth_getLogs".to_string(),
+            upstreams: upstreams.iter().map(|name| name.to_string()).collect(),
+            chain: None,
+        }
+    }
+
+    fn names(candidates: Vec<UpstreamConfig>) -> Vec<String> {
+        candidates
+            .into_iter()
+            .map(|upstream| upstream.name)
+            .collect()
+    }
+
+    #[test]
+    fn route_upstreams_are_used_for_keys_without_chain() {
+        let candidates = pool().candidates(
+            &KeyRouting::default(),
+            Some(&route(&["polygon-archive"])),
+            BalanceStrategy::Failover,
+        );
+        assert_eq!(names(candidates), vec!["polygon-archive"]);
+    }
+
+    #[test]
+    fn route_upstreams_of_other_chain_are_skipped() {
+        let routing = KeyRouting {
+            chain: Some("mumbai".to_string()),
+            ..Default::default()
+        };
+        let mut pool = pool();
+        let candidates = pool.candidates(
+            &routing,
+            Some(&route(&["polygon-archive", "mumbai-archive"])),
+            BalanceStrategy::Failover,
+        );
+        assert_eq!(names(candidates), vec!["mumbai-archive"]);
+        // no route upstream serves the chain, key routing is used
+        let candidates = pool.candidates(
+            &routing,
+            Some(&route(&["polygon-archive"])),
+            BalanceStrategy::Failover,
+        );
+        assert_eq!(names(candidates), vec!["mumbai", "mumbai-archive"]);
+    }
+
+    #[test]
+    fn chain_of_bound_upstream_restricts_route() {
+        let routing = KeyRouting {
+            upstream: Some("mumbai".to_string()),
+            ..Default::default()
+        };
+        let candidates = pool().candidates(
+            &routing,
+            Some(&route(&["polygon-archive"])),
+            BalanceStrategy::Failover,
+        );
+        assert_eq!(names(candidates), vec!["mumbai"]);
+    }
+}